@@ -0,0 +1,42 @@
+//! Counts the hashes done by rotation-heavy inserts of large values. Each
+//! node reuses its cached value hash when only its children change, so the
+//! whole value is hashed once per insert rather than on every node update.
+//! Run with `cargo bench --bench cached_value_hash --features hash-stats`.
+
+use avl::node::{hash_stats, MerkleAvlTree};
+use std::time::Instant;
+
+const ENTRIES: i32 = 20_000;
+const VALUE_LEN: usize = 4 * 1024;
+
+fn main() {
+    let value = "x".repeat(VALUE_LEN);
+
+    // Ascending keys rotate on almost every insert.
+    let mut tree = MerkleAvlTree::new();
+    let mut rotations = 0;
+    let before = hash_stats();
+    let start = Instant::now();
+    for key in 0..ENTRIES {
+        rotations += tree.insert_with_stats(key, value.clone()).unwrap().rotations;
+    }
+    let inserts = start.elapsed();
+    let after = hash_stats();
+    let value_hashes = after.value_hashes - before.value_hashes;
+    let node_hashes = after.node_hashes - before.node_hashes;
+
+    // Rehashing the value on every node update would redo this much work.
+    let saved = node_hashes - value_hashes;
+    let start = Instant::now();
+    for _ in 0..saved {
+        std::hint::black_box(tree.hash_value(std::hint::black_box(&value)));
+    }
+    let rehashing = start.elapsed();
+
+    println!("rotations:            {}", rotations);
+    println!("node hashes:          {}", node_hashes);
+    println!("value hashes:         {}", value_hashes);
+    println!("value rehashes saved: {}", saved);
+    println!("inserts:              {:?}", inserts);
+    println!("saved rehashes cost:  {:?}", rehashing);
+}
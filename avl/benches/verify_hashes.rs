@@ -0,0 +1,27 @@
+//! Times `verify_hashes` against `verify_hashes_parallel` on a 1M-entry
+//! tree. Run with `cargo bench --features parallel`.
+
+use avl::node::MerkleAvlTree;
+use std::time::Instant;
+
+const ENTRIES: usize = 1_000_000;
+
+fn main() {
+    let entries = (0..ENTRIES as i32).map(|key| (key, format!("value {}", key)));
+    let tree = MerkleAvlTree::from_sorted_iter(entries, ENTRIES);
+
+    let start = Instant::now();
+    assert!(tree.verify_hashes());
+    let serial = start.elapsed();
+
+    let start = Instant::now();
+    assert!(tree.verify_hashes_parallel());
+    let parallel = start.elapsed();
+
+    println!("verify_hashes:          {:?}", serial);
+    println!("verify_hashes_parallel: {:?}", parallel);
+    println!(
+        "speedup:                {:.2}x",
+        serial.as_secs_f64() / parallel.as_secs_f64()
+    );
+}
@@ -1,14 +1,13 @@
-pub mod node;
-use crate::node::MerkleAvlTree;
+use avl::node::MerkleAvlTree;
 fn main() {
     let mut tree = MerkleAvlTree::new();
 
     // Insert key-value pairs
-    tree.insert(10, "value10".to_string());
-    tree.insert(20, "value20".to_string());
-    tree.insert(5, "value5".to_string());
-    tree.insert(15, "value15".to_string());
-    tree.insert(25, "value25".to_string());
+    tree.insert(10, "value10".to_string()).unwrap();
+    tree.insert(20, "value20".to_string()).unwrap();
+    tree.insert(5, "value5".to_string()).unwrap();
+    tree.insert(15, "value15".to_string()).unwrap();
+    tree.insert(25, "value25".to_string()).unwrap();
 
     // Lookup keys
     println!("Lookup key 10: {:?}", tree.lookup(10));
@@ -1,300 +1,4851 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::collections::hash_map::DefaultHasher;
+use std::fmt;
 use std::hash::Hash;
 use std::hash::Hasher;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, Sender};
 
+/// A commitment: 64 bits by default, or 128 or 256 with the `hash-128` or
+/// `hash-256` feature. Proofs and their encodings carry hashes at this width.
+#[cfg(not(any(feature = "hash-128", feature = "hash-256")))]
 pub type HashType = u64;
+#[cfg(feature = "hash-128")]
+pub type HashType = u128;
+#[cfg(feature = "hash-256")]
+pub type HashType = [u8; 32];
+
+#[cfg(all(feature = "hash-128", feature = "hash-256"))]
+compile_error!("features `hash-128` and `hash-256` are mutually exclusive");
+
+/// The width of a `HashType` in bytes.
+pub const HASH_BYTES: usize = std::mem::size_of::<HashType>();
+
+/// What an absent child, or an `Empty` proof position, contributes to its
+/// parent's hash.
+pub const EMPTY_HASH: HashType = hash_from_bytes([0; HASH_BYTES]);
+
+/// The big-endian bytes of `hash`, as proofs encode it.
+#[cfg(not(feature = "hash-256"))]
+const fn hash_to_bytes(hash: HashType) -> [u8; HASH_BYTES] {
+    hash.to_be_bytes()
+}
+
+#[cfg(feature = "hash-256")]
+const fn hash_to_bytes(hash: HashType) -> [u8; HASH_BYTES] {
+    hash
+}
+
+#[cfg(not(feature = "hash-256"))]
+const fn hash_from_bytes(bytes: [u8; HASH_BYTES]) -> HashType {
+    HashType::from_be_bytes(bytes)
+}
+
+#[cfg(feature = "hash-256")]
+const fn hash_from_bytes(bytes: [u8; HASH_BYTES]) -> HashType {
+    bytes
+}
+
+/// Accumulates a `HashType` from one 64-bit hasher per 8 bytes of output,
+/// each fed the same input. Every lane but the first is first seeded with
+/// its index, so the default width's hashes are the first lane's alone.
+struct WideHasher([DefaultHasher; HASH_BYTES / 8]);
+
+impl WideHasher {
+    fn new() -> WideHasher {
+        WideHasher(std::array::from_fn(|lane| {
+            let mut hasher = DefaultHasher::new();
+            if lane > 0 {
+                lane.hash(&mut hasher);
+            }
+            hasher
+        }))
+    }
+
+    fn finish_wide(&self) -> HashType {
+        let mut bytes = [0; HASH_BYTES];
+        for (chunk, lane) in bytes.chunks_exact_mut(8).zip(&self.0) {
+            chunk.copy_from_slice(&lane.finish().to_be_bytes());
+        }
+        hash_from_bytes(bytes)
+    }
+}
+
+impl Hasher for WideHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for lane in &mut self.0 {
+            lane.write(bytes);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0[0].finish()
+    }
+}
+
+/// The hash at the top of a tree, kept distinct from keys, node hashes and
+/// other integers so they can't be passed where a root is expected.
+///
+/// ```
+/// use avl::node::{HashType, MerkleAvlTree, RootHash, HASH_BYTES};
+///
+/// let mut tree = MerkleAvlTree::new();
+/// tree.insert(1, "one".to_string()).unwrap();
+/// let root: RootHash = tree.root_hash().unwrap();
+///
+/// let raw: HashType = root.into();
+/// assert_eq!(RootHash::from(raw), root);
+/// assert_eq!(root.to_string().len(), 2 * HASH_BYTES);
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct RootHash(HashType);
+/// Keys are ordered by `Ord`, and inserting a key that compares `Equal` to a
+/// stored one replaces its value. A key type must therefore have an `Ord`
+/// consistent with `Eq`; `Node::insert` debug-asserts this.
 pub type Key = i32;
 pub type Value = String;
+/// Caller-defined location of a value stored outside the tree.
+pub type Handle = u64;
+/// Caller-chosen identifier carried by tagged proofs.
+pub type TreeId = u64;
+/// What a `with_value_index` projection maps each value to.
+pub type ValueKey = String;
+
+type Link = Option<Box<Node>>;
+
+const NO_SALT: &[u8] = &[];
+const RANGE_DOMAIN: &[u8] = b"avl/range";
+const TOMBSTONE_DOMAIN: &[u8] = b"avl/tombstone";
+/// Leading byte of `ProofNode::to_bytes`; bump whenever the encoding changes.
+pub const PROOF_VERSION: u8 = 1;
+
+/// The greatest height an AVL tree with at most `nodes` nodes can reach.
+/// The sparsest AVL tree of height `h` has `N(h) = N(h - 1) + N(h - 2) + 1`
+/// nodes, a Fibonacci number less one.
+const fn max_avl_height(nodes: usize) -> i32 {
+    let (mut height, mut sparsest, mut below) = (0, 0u128, 0u128);
+    loop {
+        let next = if height == 0 { 1 } else { sparsest + below + 1 };
+        if next > nodes as u128 {
+            return height;
+        }
+        (height, below, sparsest) = (height + 1, sparsest, next);
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Error {
+    NotFound,
+    Empty,
+    InvalidProof,
+    MalformedProof,
+    ValueTooLarge,
+    KeyMismatch,
+    WrongTree,
+    UnsupportedProofVersion,
+    ProofTooDeep,
+    HashCollision,
+    DuplicateKeyAfterRemap,
+    PatchConflict,
+    /// Entry `at_index` has a smaller key than the one before it.
+    NotSorted { at_index: usize },
+    DuplicateKey { key: Key },
+    /// The key is held by a `soft_delete` tombstone.
+    Deleted,
+    /// The proofs leave part of the tree unknown.
+    Incomplete,
+    KeyOutOfRange,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Operation {
+    Insert(Key, Value),
+    Delete(Key),
+    Lookup(Key),
+}
+
+/// A path from the root down to a key.
+///
+/// `Left` and `Right` record a node on the path (its key, value hash and the
+/// hash of the child not taken), `Leaf` is the node holding the key together
+/// with its children's hashes, and `Empty` marks where an absent key would sit.
+/// `EmptyLeft` and `EmptyRight` are the compacted form of a `Left` or `Right`
+/// step into an `Empty` child. `Tombstone` stands in for `Leaf` when the key
+/// was removed by `soft_delete`, and `LeafHash` when the prover withholds the
+/// value, keeping only its hash; see `generate_proof_hidden`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ProofNode {
+    Left(Key, HashType, Box<ProofNode>, Option<HashType>),
+    Right(Key, HashType, Option<HashType>, Box<ProofNode>),
+    Leaf(Key, Value, Option<HashType>, Option<HashType>),
+    Empty,
+    EmptyLeft(Key, HashType, Option<HashType>),
+    EmptyRight(Key, HashType, Option<HashType>),
+    Tombstone(Key, Option<HashType>, Option<HashType>),
+    LeafHash(Key, HashType, Option<HashType>, Option<HashType>),
+}
+
+static EMPTY_PROOF: ProofNode = ProofNode::Empty;
+
+#[cfg(test)]
+thread_local! {
+    static PROOFS_GENERATED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(any(test, feature = "hash-stats"))]
+thread_local! {
+    static VALUE_HASHES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    static NODE_HASHES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// How many hashes this thread has computed so far; see `hash_stats`.
+#[cfg(feature = "hash-stats")]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct HashStats {
+    /// Hashes of a whole value, done once per stored or replaced value.
+    pub value_hashes: usize,
+    /// Node hashes, recombining a key, a cached value hash and two child
+    /// hashes.
+    pub node_hashes: usize,
+}
+
+/// Reads this thread's running hash counts. Diff two readings to see what
+/// the operations between them cost.
+#[cfg(feature = "hash-stats")]
+pub fn hash_stats() -> HashStats {
+    HashStats {
+        value_hashes: VALUE_HASHES.with(|count| count.get()),
+        node_hashes: NODE_HASHES.with(|count| count.get()),
+    }
+}
+
+/// Proves that a tree which has only ever had strictly ascending keys
+/// appended kept every entry an older root committed to. Lists the value
+/// hash of every entry, split at the older size; see
+/// `generate_consistency_proof`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ConsistencyProof {
+    pub old: Vec<(Key, HashType)>,
+    pub appended: Vec<(Key, HashType)>,
+}
+
+/// Proves that a leaf is under one peak of the Merkle mountain range from
+/// `to_mmr_peaks`: the leaf's index within that peak's perfect subtree and
+/// the sibling hashes from the leaf up to the peak.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MmrProof {
+    pub peak: usize,
+    pub position: usize,
+    pub siblings: Vec<HashType>,
+}
+
+/// Proves that `entries` are all the entries with keys in `[lo, hi]`: a
+/// proof for `lo` itself, a membership proof of each later in-range entry,
+/// and of the first entry after `hi` if there is one. Every proof must be
+/// the direct successor of the one before it, so none can be left out.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CompletenessProof {
+    pub lo: Key,
+    pub hi: Key,
+    /// Membership proof of `lo` when it is present, absence proof otherwise.
+    pub start: ProofNode,
+    pub entries: Vec<ProofNode>,
+    pub end: Option<ProofNode>,
+}
+
+/// Proves where `key` sits in the order: its membership or absence proof plus
+/// proofs of the entries immediately before and after it, if any.
+#[derive(Debug, PartialEq, Clone)]
+pub struct NeighborProof {
+    pub key: Key,
+    pub target: ProofNode,
+    pub predecessor: Option<ProofNode>,
+    pub successor: Option<ProofNode>,
+}
+
+/// A summary of a tree's size, shape, commitment and health, from
+/// `audit_report`. Its `Display` renders one field per line.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AuditReport {
+    pub len: usize,
+    pub height: i32,
+    pub balance_score: f64,
+    pub root_hash: Option<RootHash>,
+    pub min_key: Option<Key>,
+    pub max_key: Option<Key>,
+    /// The first violation `find_invariant_violation` reports, if any.
+    pub violation: Option<Violation>,
+}
+
+/// The shape of a tree without its values or hashes, from `skeleton`.
+/// `Display` renders one node per line as `key (height)`, children indented
+/// below their parent, left first, with `-` for a missing child that has a
+/// sibling.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Skeleton {
+    pub key: Key,
+    pub height: i32,
+    pub left: Option<Box<Skeleton>>,
+    pub right: Option<Box<Skeleton>>,
+}
+
+/// A broken tree invariant found by `find_invariant_violation`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Violation {
+    pub key: Key,
+    pub kind: ViolationKind,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ViolationKind {
+    /// Child heights differ by more than one; carries right minus left.
+    Unbalanced { balance: i32 },
+    BadHeight { expected: i32, actual: i32 },
+    BadHash { expected: HashType, actual: HashType },
+    /// The key lies outside the `(lower, upper)` range its ancestors allow.
+    OutOfOrder {
+        lower: Option<Key>,
+        upper: Option<Key>,
+    },
+}
+
+/// The hash a failed proof yields at each level, from `debug_verify`.
+/// Level 0 is the root.
+#[derive(Debug, PartialEq, Clone)]
+pub struct VerifyTrace {
+    /// The root the proof was checked against.
+    pub root_hash: RootHash,
+    pub levels: Vec<TraceLevel>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceLevel {
+    /// The key the proof records at this level, `None` for an empty position.
+    pub key: Option<Key>,
+    pub computed: HashType,
+    /// Whether the proof continues into the left child, `None` at the end.
+    pub went_left: Option<bool>,
+}
+
+/// A captured tree state that `rollback` can restore.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Savepoint {
+    tree: MerkleAvlTree,
+}
+
+/// A proof labelled with the id of the tree that produced it, so that
+/// verifying it against another tree's root reports `Error::WrongTree`
+/// instead of a bare `InvalidProof`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TaggedProof {
+    pub tree_id: Option<TreeId>,
+    pub proof: ProofNode,
+}
+
+/// What a single `insert_with_stats` call did to the tree's shape.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct InsertStats {
+    /// Single rotations performed while rebalancing; a double rotation
+    /// counts as two.
+    pub rotations: usize,
+}
+
+/// The gaps between consecutive keys, from `key_spacing_stats`. A gap is
+/// the difference of two adjacent keys, which for `i32` keys always fits a
+/// `u64`. All zero when there are fewer than two keys.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct SpacingStats {
+    pub gaps: usize,
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    /// Population standard deviation.
+    pub stddev: f64,
+}
+
+/// The changes that turn one tree's entries into another's, from
+/// `make_patch`, in ascending key order.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Patch {
+    pub ops: Vec<PatchOp>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum PatchOp {
+    /// Adds a key the patched tree must not hold yet.
+    Insert(Key, Value),
+    Delete(Key),
+    /// Replaces the value of a key the patched tree must already hold.
+    Update(Key, Value),
+}
+
+/// One key of a `merge_join` over two trees.
+#[derive(Debug, PartialEq, Clone)]
+pub enum MergeItem<'a> {
+    Both(&'a Key, Cow<'a, Value>, Cow<'a, Value>),
+    OnlyLeft(&'a Key, Cow<'a, Value>),
+    OnlyRight(&'a Key, Cow<'a, Value>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MerkleAvlTree {
+    root: Option<Box<Node>>,
+    len: usize,
+    tombstones: usize,
+    salt: Vec<u8>,
+    lru: Option<RefCell<LruOrder>>,
+    max_value_len: Option<usize>,
+    key_range: Option<(Key, Key)>,
+    rebuild_threshold: Option<f64>,
+    deletes_since_rebuild: usize,
+    rebuilds: usize,
+    tree_id: Option<TreeId>,
+    probe: Probe,
+    proof_cache: Option<RefCell<CachedProofs>>,
+    value_index: Option<ValueIndex>,
+    /// Every live entry by its leaf hash, under `with_collision_check`.
+    leaf_hashes: Option<HashMap<HashType, (Key, Value)>>,
+    codec: Option<ValueCodec>,
+    subscribers: Subscribers,
+}
+
+/// The channels `subscribe` handed out. Like `Probe` they observe the tree
+/// without being part of its state; a clone starts with none, so that only
+/// the subscribed tree reports to them.
+#[derive(Debug, Default)]
+struct Subscribers(Vec<Sender<(Operation, HashType)>>);
+
+impl Subscribers {
+    /// Sends to every live subscriber, dropping those whose receiver is gone.
+    fn send(&mut self, op: Operation, root: HashType) {
+        self.0.retain(|subscriber| subscriber.send((op.clone(), root)).is_ok());
+    }
+}
+
+impl Clone for Subscribers {
+    fn clone(&self) -> Subscribers {
+        Subscribers::default()
+    }
+}
+
+impl PartialEq for Subscribers {
+    fn eq(&self, _: &Subscribers) -> bool {
+        true
+    }
+}
+
+/// The value encoding installed by `with_value_codec`.
+#[derive(Clone)]
+struct ValueCodec {
+    compress: Rc<dyn Fn(&Value) -> Value>,
+    decompress: Rc<dyn Fn(&Value) -> Value>,
+}
+
+impl fmt::Debug for ValueCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ValueCodec(..)")
+    }
+}
+
+impl ValueCodec {
+    /// `value` as it was inserted: borrowed as stored without a codec,
+    /// decompressed with one.
+    fn decode<'a>(codec: Option<&ValueCodec>, value: &'a Value) -> Cow<'a, Value> {
+        match codec {
+            Some(codec) => Cow::Owned((codec.decompress)(value)),
+            None => Cow::Borrowed(value),
+        }
+    }
+}
+
+/// The codec only changes how values are stored; the stored values are
+/// compared directly.
+impl PartialEq for ValueCodec {
+    fn eq(&self, _: &ValueCodec) -> bool {
+        true
+    }
+}
+
+/// Reverse index from a projection of each value to the keys holding it,
+/// each list in ascending order.
+#[derive(Clone)]
+struct ValueIndex {
+    projection: Rc<dyn Fn(&Value) -> ValueKey>,
+    keys: HashMap<ValueKey, Vec<Key>>,
+}
+
+impl fmt::Debug for ValueIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValueIndex").field("keys", &self.keys).finish()
+    }
+}
+
+impl PartialEq for ValueIndex {
+    fn eq(&self, other: &ValueIndex) -> bool {
+        self.keys == other.keys
+    }
+}
+
+/// Hit and miss counts of a tree's proof cache.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct ProofCacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+/// Recently generated proofs, all valid against `root`. Any other root
+/// empties the cache, so entries are effectively keyed by (key, root).
+#[derive(Debug, Clone)]
+struct CachedProofs {
+    root: Option<HashType>,
+    order: LruOrder,
+    proofs: HashMap<Key, ProofNode>,
+    stats: ProofCacheStats,
+}
+
+/// Cached proofs are derived data; trees compare equal whatever they hold.
+impl PartialEq for CachedProofs {
+    fn eq(&self, other: &CachedProofs) -> bool {
+        self.order.capacity == other.order.capacity
+    }
+}
+
+/// A node visited on a search path, as reported to a `with_probe` callback.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct NodeView {
+    pub key: Key,
+    /// Distance from the root, which is at depth 0.
+    pub depth: usize,
+}
+
+/// The callback installed by `with_probe`, if any. It observes the tree
+/// without being part of its state, so it never affects equality.
+#[derive(Clone, Default)]
+struct Probe(Option<Rc<dyn Fn(NodeView)>>);
+
+impl fmt::Debug for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0.is_some() { "Probe(Some(..))" } else { "Probe(None)" })
+    }
+}
+
+impl PartialEq for Probe {
+    fn eq(&self, _: &Probe) -> bool {
+        true
+    }
+}
+
+/// Access order for a capacity-bounded tree; a larger tick is more recent.
+#[derive(Debug, PartialEq, Clone)]
+struct LruOrder {
+    capacity: usize,
+    tick: u64,
+    ticks: HashMap<Key, u64>,
+    order: BTreeMap<u64, Key>,
+}
+
+/// Computes the root `from_sorted` would build over a stream of entries
+/// pushed one at a time, keeping only the hashes along the current path.
+/// The entry count must be known up front, since it fixes the shape.
+///
+/// ```
+/// use avl::node::{MerkleAvlTree, StreamingCommitter};
+///
+/// let pairs: Vec<_> = (0..5).map(|key| (key, key.to_string())).collect();
+/// let mut committer = StreamingCommitter::new(pairs.len());
+/// for (key, value) in &pairs {
+///     committer.push(*key, value);
+/// }
+/// assert_eq!(MerkleAvlTree::from_sorted(pairs).root_hash(), committer.finalize());
+/// ```
+#[derive(Debug, Clone)]
+pub struct StreamingCommitter {
+    stack: Vec<CommitFrame>,
+    root: Option<HashType>,
+    previous: Option<Key>,
+}
+
+/// A subtree of `len` entries still being committed: its left subtree's
+/// hash once known, then its own key and value hash.
+#[derive(Debug, Clone)]
+struct CommitFrame {
+    len: usize,
+    left: Option<Option<HashType>>,
+    node: Option<(Key, HashType)>,
+}
+
+/// A batch of reads under one shared borrow of a tree, from
+/// `MerkleAvlTree::read_tx`. Every value it returns lives as long as the
+/// borrow, so any number can be held at once.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadTx<'a> {
+    tree: &'a MerkleAvlTree,
+}
+
+/// Generates proofs like `MerkleAvlTree::generate_proof` while remembering
+/// the last search path, from `MerkleAvlTree::proof_context`. Each `prove`
+/// resumes from the deepest remembered node whose subtree can hold the key,
+/// so proofs for a sorted batch of nearby keys skip their shared descent.
+#[derive(Debug, Clone)]
+pub struct ProofContext<'a> {
+    tree: &'a MerkleAvlTree,
+    /// The last path, root first, each node with the exclusive key bounds
+    /// of its subtree.
+    path: Vec<(&'a Node, Option<Key>, Option<Key>)>,
+    /// Levels taken from `path` instead of being searched again.
+    reused: usize,
+}
+
+/// The subtree hashes a verified proof reveals, from
+/// `MerkleAvlTree::cache_from_proof`: every node on its path and both
+/// children of each. A later proof for a nearby key need only reach down
+/// from the deepest of these on its own path; see `shrink` and
+/// `MerkleAvlTree::verify_membership_with_cache`.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ProofCache {
+    /// The proof's levels, root first.
+    levels: Vec<CachedLevel>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct CachedLevel {
+    key: Key,
+    hash: HashType,
+    left: Option<HashType>,
+    right: Option<HashType>,
+    /// Which way the cached path turned here; `None` at the proved node.
+    went_left: Option<bool>,
+}
+
+/// A tree that can no longer change, from `MerkleAvlTree::freeze`. It only
+/// offers reads and proofs; `thaw` turns it back into a mutable tree.
+///
+/// ```compile_fail
+/// use avl::node::MerkleAvlTree;
+///
+/// let mut frozen = MerkleAvlTree::new().freeze();
+/// frozen.insert(1, "one".to_string());
+/// ```
+#[derive(Debug, PartialEq, Clone)]
+pub struct FrozenTree {
+    tree: MerkleAvlTree,
+}
+
+/// A tree whose leaves keep only the hash of each value plus a handle to
+/// where the bytes live. It commits to exactly the same root as a
+/// `MerkleAvlTree` holding the values inline.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct OutOfLineTree {
+    tree: MerkleAvlTree,
+    handles: BTreeMap<Key, Handle>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Node {
+    key: Key,
+    value: Value,
+    value_hash: HashType,
+    hash: HashType,
+    height: i32,
+    left: Option<Box<Node>>,
+    right: Option<Box<Node>>,
+}
+
+impl From<HashType> for RootHash {
+    fn from(hash: HashType) -> Self {
+        RootHash(hash)
+    }
+}
+
+impl From<RootHash> for HashType {
+    fn from(root: RootHash) -> Self {
+        root.0
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Error::NotSorted { at_index } => {
+                return write!(f, "entry {} is not in ascending key order", at_index)
+            }
+            Error::DuplicateKey { key } => {
+                return write!(f, "key {} appears more than once", key)
+            }
+            Error::NotFound => "key not found",
+            Error::Empty => "tree is empty",
+            Error::InvalidProof => "proof does not verify against the root",
+            Error::MalformedProof => "proof is malformed",
+            Error::ValueTooLarge => "value exceeds the tree's maximum length",
+            Error::KeyMismatch => "proof is for a different key",
+            Error::WrongTree => "proof was generated by a different tree",
+            Error::UnsupportedProofVersion => "proof encoding version is not supported",
+            Error::ProofTooDeep => "proof is deeper than the tree can be",
+            Error::HashCollision => "two distinct entries have the same leaf hash",
+            Error::DuplicateKeyAfterRemap => "key remapping sent two keys to the same key",
+            Error::PatchConflict => "patch does not apply to this tree",
+            Error::Deleted => "key has been deleted",
+            Error::Incomplete => "proofs do not cover the whole tree",
+            Error::KeyOutOfRange => "key is outside the tree's key range",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for RootHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        hash_to_bytes(self.0)
+            .iter()
+            .try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+impl fmt::LowerHex for RootHash {
+    #[cfg(not(feature = "hash-256"))]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+
+    #[cfg(feature = "hash-256")]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for MerkleAvlTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.root_hash() {
+            Some(root) => write!(f, "MerkleAvlTree {{ len: {}, root: 0x{} }}", self.len(), root),
+            None => write!(f, "MerkleAvlTree {{ len: 0, root: empty }}"),
+        }
+    }
+}
+
+impl fmt::Display for AuditReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn or_none(key: Option<Key>) -> String {
+            key.map_or_else(|| "none".to_string(), |key| key.to_string())
+        }
+        writeln!(f, "entries:       {}", self.len)?;
+        writeln!(f, "height:        {}", self.height)?;
+        writeln!(f, "balance score: {:.3}", self.balance_score)?;
+        match self.root_hash {
+            Some(root) => writeln!(f, "root hash:     0x{}", root)?,
+            None => writeln!(f, "root hash:     empty")?,
+        }
+        writeln!(f, "min key:       {}", or_none(self.min_key))?;
+        writeln!(f, "max key:       {}", or_none(self.max_key))?;
+        match &self.violation {
+            Some(violation) => write!(f, "violation:     {:?}", violation),
+            None => write!(f, "violation:     none"),
+        }
+    }
+}
+
+impl fmt::Display for Skeleton {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn render(skeleton: &Skeleton, depth: usize, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let indent = depth * 2;
+            writeln!(f, "{:indent$}{} ({})", "", skeleton.key, skeleton.height)?;
+            if skeleton.left.is_none() && skeleton.right.is_none() {
+                return Ok(());
+            }
+            for child in [&skeleton.left, &skeleton.right] {
+                match child {
+                    Some(child) => render(child, depth + 1, f)?,
+                    None => writeln!(f, "{:indent$}  -", "")?,
+                }
+            }
+            Ok(())
+        }
+        render(self, 0, f)
+    }
+}
+
+impl From<BTreeMap<Key, Value>> for MerkleAvlTree {
+    fn from(map: BTreeMap<Key, Value>) -> Self {
+        let len = map.len();
+        MerkleAvlTree::from_sorted_iter(map.into_iter(), len)
+    }
+}
+
+impl From<MerkleAvlTree> for BTreeMap<Key, Value> {
+    fn from(tree: MerkleAvlTree) -> Self {
+        tree.into_sorted_vec().into_iter().collect()
+    }
+}
+
+impl Default for MerkleAvlTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MerkleAvlTree {
+    /// The greatest height any tree can have, that of the sparsest AVL tree
+    /// within `usize::MAX` entries: 91 on 64-bit targets. A proof passes
+    /// through at most this many nodes.
+    pub const MAX_HEIGHT: i32 = max_avl_height(usize::MAX);
+
+    pub fn new() -> MerkleAvlTree {
+        MerkleAvlTree {
+            root: None,
+            len: 0,
+            tombstones: 0,
+            salt: Vec::new(),
+            lru: None,
+            max_value_len: None,
+            key_range: None,
+            rebuild_threshold: None,
+            deletes_since_rebuild: 0,
+            rebuilds: 0,
+            tree_id: None,
+            probe: Probe::default(),
+            proof_cache: None,
+            value_index: None,
+            leaf_hashes: None,
+            codec: None,
+            subscribers: Subscribers::default(),
+        }
+    }
+
+    /// Creates a tree that remembers every entry by its leaf hash and
+    /// refuses, with `Error::HashCollision`, an insert whose leaf hash
+    /// matches a different entry's. A 64-bit hash makes such collisions
+    /// possible, and one would let a proof for either entry pass for the
+    /// other. Costs a copy of every entry; meant for tests and validation.
+    pub fn with_collision_check() -> MerkleAvlTree {
+        MerkleAvlTree {
+            leaf_hashes: Some(HashMap::new()),
+            ..MerkleAvlTree::new()
+        }
+    }
+
+    /// Creates a tree that stores every value as `compress` of it while
+    /// committing to the hash of the original, so its root equals that of a
+    /// plain tree with the same entries. `lookup`, iteration, proofs and the
+    /// other accessors give back the original, decompressing on each read,
+    /// and values are compared in that form. `decompress` must invert
+    /// `compress`.
+    pub fn with_value_codec(
+        compress: impl Fn(&Value) -> Value + 'static,
+        decompress: impl Fn(&Value) -> Value + 'static,
+    ) -> MerkleAvlTree {
+        MerkleAvlTree {
+            codec: Some(ValueCodec {
+                compress: Rc::new(compress),
+                decompress: Rc::new(decompress),
+            }),
+            ..MerkleAvlTree::new()
+        }
+    }
+
+    /// Creates a tree that indexes its keys by `projection` of their values,
+    /// answering `keys_for` without a scan at the cost of one index entry
+    /// per key. Overwrites and deletes keep the index in step.
+    pub fn with_value_index(projection: impl Fn(&Value) -> ValueKey + 'static) -> MerkleAvlTree {
+        MerkleAvlTree {
+            value_index: Some(ValueIndex {
+                projection: Rc::new(projection),
+                keys: HashMap::new(),
+            }),
+            ..MerkleAvlTree::new()
+        }
+    }
+
+    /// The keys, ascending, whose value projects to `value_key` under the
+    /// `with_value_index` projection; always empty without an index.
+    pub fn keys_for(&self, value_key: &ValueKey) -> &[Key] {
+        self.value_index
+            .as_ref()
+            .and_then(|index| index.keys.get(value_key))
+            .map_or(&[], |keys| keys.as_slice())
+    }
+
+    /// Creates a tree that keeps the last `capacity` proofs it generated for
+    /// the current root, so repeated `generate_proof` calls for the same key
+    /// are served from memory until the tree changes.
+    pub fn with_proof_cache(capacity: usize) -> MerkleAvlTree {
+        assert!(capacity > 0, "proof cache capacity must be non-zero");
+        MerkleAvlTree {
+            proof_cache: Some(RefCell::new(CachedProofs::new(capacity))),
+            ..MerkleAvlTree::new()
+        }
+    }
+
+    /// Hits and misses of the proof cache; all zero without one.
+    pub fn proof_cache_stats(&self) -> ProofCacheStats {
+        self.proof_cache
+            .as_ref()
+            .map(|cache| cache.borrow().stats)
+            .unwrap_or_default()
+    }
+
+    /// Creates a tree that calls `probe` on every node along the search path
+    /// of each `lookup`, `insert` and `delete`, root first. Trees without a
+    /// probe skip the walk entirely.
+    pub fn with_probe(probe: impl Fn(NodeView) + 'static) -> MerkleAvlTree {
+        MerkleAvlTree {
+            probe: Probe(Some(Rc::new(probe))),
+            ..MerkleAvlTree::new()
+        }
+    }
+
+    /// Reports the search path for `key` to the probe, if there is one.
+    fn probe_path(&self, key: Key) {
+        let Probe(Some(probe)) = &self.probe else {
+            return;
+        };
+        let mut current = &self.root;
+        let mut depth = 0;
+        while let Some(n) = current {
+            probe(NodeView { key: n.key, depth });
+            current = match key.cmp(&n.key) {
+                Ordering::Less => &n.left,
+                Ordering::Greater => &n.right,
+                Ordering::Equal => return,
+            };
+            depth += 1;
+        }
+    }
+
+    /// Creates a tree whose tagged proofs carry `id`.
+    pub fn with_tree_id(id: TreeId) -> MerkleAvlTree {
+        MerkleAvlTree {
+            tree_id: Some(id),
+            ..MerkleAvlTree::new()
+        }
+    }
+
+    pub fn tree_id(&self) -> Option<TreeId> {
+        self.tree_id
+    }
+
+    /// Creates a tree that rebuilds itself into the canonical `from_sorted`
+    /// shape once the deletes since the last rebuild exceed `fraction` of
+    /// its current length.
+    pub fn with_rebuild_threshold(fraction: f64) -> MerkleAvlTree {
+        MerkleAvlTree {
+            rebuild_threshold: Some(fraction),
+            ..MerkleAvlTree::new()
+        }
+    }
+
+    /// Creates a tree that refuses values longer than `max_len` bytes.
+    pub fn with_max_value_len(max_len: usize) -> MerkleAvlTree {
+        MerkleAvlTree {
+            max_value_len: Some(max_len),
+            ..MerkleAvlTree::new()
+        }
+    }
+
+    /// Creates a tree that refuses, with `Error::KeyOutOfRange`, inserts of
+    /// keys outside `[lo, hi]`, such as one shard of a key space.
+    pub fn with_key_range(lo: Key, hi: Key) -> MerkleAvlTree {
+        MerkleAvlTree {
+            key_range: Some((lo, hi)),
+            ..MerkleAvlTree::new()
+        }
+    }
+
+    /// Creates a tree holding at most `capacity` entries. Inserting a new key
+    /// into a full tree evicts the least recently inserted or looked-up key,
+    /// so the root only ever commits to live entries.
+    pub fn with_lru_capacity(capacity: usize) -> MerkleAvlTree {
+        assert!(capacity > 0, "LRU capacity must be non-zero");
+        MerkleAvlTree {
+            lru: Some(RefCell::new(LruOrder::new(capacity))),
+            ..MerkleAvlTree::new()
+        }
+    }
+
+    /// Creates a tree whose commitment is hidden behind `salt`.
+    ///
+    /// The salt is mixed into every value hash, and so into every node hash
+    /// above it. Identical contents under different salts produce unrelated
+    /// roots, and sibling hashes in a proof can't be brute-forced back to
+    /// small values by anyone who doesn't hold the salt. Keys on a proof path
+    /// are still revealed. Verifiers must be given the salt and use
+    /// `verify_proof_with_salt`. An empty salt is the same as `new`.
+    pub fn with_salt(salt: &[u8]) -> MerkleAvlTree {
+        MerkleAvlTree {
+            salt: salt.to_vec(),
+            ..MerkleAvlTree::new()
+        }
+    }
+
+    /// Builds the canonical balanced tree over `pairs`, which must be sorted
+    /// by strictly ascending key: the middle entry (index `len / 2`) becomes
+    /// the root and each half is built the same way.
+    pub fn from_sorted(pairs: Vec<(Key, Value)>) -> MerkleAvlTree {
+        let len = pairs.len();
+        Self::from_sorted_iter(pairs.into_iter(), len)
+    }
+
+    /// Builds the same tree as `from_sorted`, first checking that `pairs` is
+    /// sorted by strictly ascending key in every build mode. Reports the
+    /// first offending entry: `Error::NotSorted` for a key below its
+    /// predecessor, `Error::DuplicateKey` for a key equal to it.
+    pub fn try_from_sorted(pairs: Vec<(Key, Value)>) -> Result<MerkleAvlTree, Error> {
+        for (at_index, pair) in pairs.windows(2).enumerate().map(|(i, pair)| (i + 1, pair)) {
+            match pair[0].0.cmp(&pair[1].0) {
+                Ordering::Less => {}
+                Ordering::Equal => return Err(Error::DuplicateKey { key: pair[1].0 }),
+                Ordering::Greater => return Err(Error::NotSorted { at_index }),
+            }
+        }
+        Ok(Self::from_sorted(pairs))
+    }
+
+    /// Rebuilds the unsalted tree with root `root_hash` from membership
+    /// proofs of its entries. Every proof must verify against the root; the
+    /// paths give the shape and each key's own proof its value, so a key on
+    /// some path, or a child some proof commits to, without a proof of its
+    /// own gives `Error::Incomplete`.
+    pub fn from_proofs(proofs: &[ProofNode], root_hash: RootHash) -> Result<MerkleAvlTree, Error> {
+        type Leaves<'a> = HashMap<Key, (&'a Value, bool, bool)>;
+        type Children = HashMap<Key, (Option<Key>, Option<Key>)>;
+        fn build(key: Key, leaves: &Leaves, children: &Children) -> Result<Box<Node>, Error> {
+            let &(value, has_left, has_right) = leaves.get(&key).ok_or(Error::Incomplete)?;
+            let (left, right) = children.get(&key).copied().unwrap_or_default();
+            let child = |child: Option<Key>, committed: bool| match child {
+                Some(child) if committed => build(child, leaves, children).map(Some),
+                None if !committed => Ok(None),
+                _ => Err(Error::Incomplete),
+            };
+            let mut node = Node::new(key, value.clone(), Node::value_hash(NO_SALT, value));
+            node.left = child(left, has_left)?;
+            node.right = child(right, has_right)?;
+            Node::update_height_and_hash(&mut node);
+            Ok(node)
+        }
+
+        let mut leaves = Leaves::new();
+        let mut children = Children::new();
+        let mut root_key = None;
+        for proof in proofs {
+            let (key, value) = Self::verify_proof(proof, root_hash)?;
+            let (steps, terminal) = proof.steps();
+            let (ProofNode::Leaf(_, _, left, right), true) = (terminal, proof.follows(*key)) else {
+                return Err(Error::InvalidProof);
+            };
+            leaves.insert(*key, (value, left.is_some(), right.is_some()));
+            let below = steps.iter().skip(1).map(|&(step_key, _)| step_key);
+            for (&(parent, went_left), child) in steps.iter().zip(below.chain([*key])) {
+                let entry = children.entry(parent).or_default();
+                if went_left {
+                    entry.0 = Some(child);
+                } else {
+                    entry.1 = Some(child);
+                }
+            }
+            root_key = Some(steps.first().map_or(*key, |&(step_key, _)| step_key));
+        }
+        let root = build(root_key.ok_or(Error::Incomplete)?, &leaves, &children)?;
+        if root.hash != root_hash.0 {
+            return Err(Error::InvalidProof);
+        }
+        Ok(MerkleAvlTree {
+            root: Some(root),
+            len: leaves.len(),
+            ..MerkleAvlTree::new()
+        })
+    }
+
+    /// The root `from_sorted(pairs)` would have, computed straight from the
+    /// slice without building nodes; `None` for no pairs.
+    pub fn commit_sorted(pairs: &[(Key, Value)]) -> Option<RootHash> {
+        fn commit(pairs: &[(Key, Value)]) -> Option<HashType> {
+            if pairs.is_empty() {
+                return None;
+            }
+            let mid = pairs.len() / 2;
+            let (key, value) = &pairs[mid];
+            let value_hash = Node::value_hash(NO_SALT, value);
+            let (left, right) = (commit(&pairs[..mid]), commit(&pairs[mid + 1..]));
+            Some(Node::compute_hash(key, value_hash, left, right))
+        }
+        commit(pairs).map(RootHash)
+    }
+
+    /// Builds the same tree as `from_sorted` while pulling entries one at a
+    /// time, holding only the current path in memory. `iter` must yield
+    /// exactly `hint_len` entries in strictly ascending key order.
+    pub fn from_sorted_iter(
+        mut iter: impl Iterator<Item = (Key, Value)>,
+        hint_len: usize,
+    ) -> MerkleAvlTree {
+        let mut hashed = iter.by_ref().map(|(key, value)| {
+            let value_hash = Node::value_hash(NO_SALT, &value);
+            (key, value, value_hash)
+        });
+        let mut previous = None;
+        MerkleAvlTree {
+            root: Node::build_sorted(&mut hashed, &mut previous, hint_len),
+            len: hint_len,
+            ..MerkleAvlTree::new()
+        }
+    }
+
+    /// Builds a new canonical `from_sorted` tree over the entries with keys
+    /// in `[lo, hi]`, keeping this tree's salt and value codec and reusing
+    /// its value hashes. Its `range_root(lo, hi)` equals this tree's.
+    pub fn clone_range(&self, lo: Key, hi: Key) -> MerkleAvlTree {
+        let mut entries = Vec::new();
+        Node::visit_range(&self.root, lo, hi, &mut |n| {
+            entries.push((n.key, n.value.clone(), n.value_hash))
+        });
+        MerkleAvlTree {
+            codec: self.codec.clone(),
+            ..Self::from_hashed_sorted(entries, self.salt.clone())
+        }
+    }
+
+    /// Splits the entries by rank into `n` contiguous key ranges whose sizes,
+    /// not counting `soft_delete` tombstones, differ by at most one, earlier
+    /// shards taking the extra entries. Each shard is a canonical
+    /// `from_sorted` tree keeping this tree's salt and value codec.
+    pub fn shard(self, n: usize) -> Vec<MerkleAvlTree> {
+        assert!(n > 0, "cannot split into zero shards");
+        let (base, extra) = (self.len() / n, self.len() % n);
+        let mut entries = Vec::with_capacity(self.len);
+        Node::drain_sorted(self.root, &mut entries);
+        let mut entries = entries.into_iter().peekable();
+        (0..n)
+            .map(|i| {
+                // Tombstones ride along with the live entries around them and
+                // don't count towards a shard's size; the last shard takes any
+                // left at the end.
+                let mut live = base + usize::from(i < extra);
+                let mut shard = Vec::new();
+                while live > 0 || (i + 1 == n && entries.peek().is_some()) {
+                    let Some(entry) = entries.next() else { break };
+                    if !Node::is_tombstone_entry(&entry.1, entry.2) {
+                        live -= 1;
+                    }
+                    shard.push(entry);
+                }
+                MerkleAvlTree {
+                    codec: self.codec.clone(),
+                    ..Self::from_hashed_sorted(shard, self.salt.clone())
+                }
+            })
+            .collect()
+    }
+
+    /// Rebuilds the tree with every key replaced by `f(key)`, as a canonical
+    /// `from_sorted` tree keeping this tree's salt and value codec, so values
+    /// aren't rehashed.
+    /// `f` need not preserve order. Fails with `Error::DuplicateKeyAfterRemap`
+    /// if `f` sends two keys to the same key.
+    pub fn remap_keys(self, f: impl Fn(Key) -> Key) -> Result<MerkleAvlTree, Error> {
+        let mut entries = Vec::with_capacity(self.len);
+        Node::drain_sorted(self.root, &mut entries);
+        for entry in &mut entries {
+            entry.0 = f(entry.0);
+        }
+        entries.sort_unstable_by_key(|entry| entry.0);
+        if entries.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            return Err(Error::DuplicateKeyAfterRemap);
+        }
+        Ok(MerkleAvlTree {
+            codec: self.codec,
+            ..Self::from_hashed_sorted(entries, self.salt)
+        })
+    }
+
+    /// The canonical tree over already hashed entries in ascending key order.
+    fn from_hashed_sorted(entries: Vec<(Key, Value, HashType)>, salt: Vec<u8>) -> MerkleAvlTree {
+        let len = entries.len();
+        let tombstones = entries
+            .iter()
+            .filter(|(_, value, value_hash)| Node::is_tombstone_entry(value, *value_hash))
+            .count();
+        let mut previous = None;
+        MerkleAvlTree {
+            root: Node::build_sorted(&mut entries.into_iter(), &mut previous, len),
+            len,
+            tombstones,
+            salt,
+            ..MerkleAvlTree::new()
+        }
+    }
+
+    /// Encodes the entries, independent of tree shape and salt, as an 8-byte
+    /// big-endian entry count followed by each entry in ascending key order:
+    /// a 4-byte big-endian key, a 4-byte big-endian value length, then the
+    /// value's UTF-8 bytes. Trees with equal contents give equal bytes.
+    pub fn canonical_contents_bytes(&self) -> Vec<u8> {
+        let mut bytes = (self.len() as u64).to_be_bytes().to_vec();
+        for (key, value) in self.iter() {
+            bytes.extend_from_slice(&key.to_be_bytes());
+            bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(value.as_bytes());
+        }
+        bytes
+    }
+
+    /// Consumes the tree, returning its entries in ascending key order.
+    /// `soft_delete` tombstones are left out.
+    pub fn into_sorted_vec(self) -> Vec<(Key, Value)> {
+        let mut entries = Vec::with_capacity(self.len);
+        Node::drain_sorted(self.root, &mut entries);
+        entries
+            .into_iter()
+            .filter(|(_, value, value_hash)| !Node::is_tombstone_entry(value, *value_hash))
+            .map(|(key, value, _)| (key, Self::decoded(&self.codec, value)))
+            .collect()
+    }
+
+    pub fn insert(&mut self, key: Key, value: Value) -> Result<(), Error> {
+        self.insert_with_stats(key, value).map(|_| ())
+    }
+
+    /// Inserts like `insert`, also reporting how much rebalancing it took.
+    pub fn insert_with_stats(&mut self, key: Key, value: Value) -> Result<InsertStats, Error> {
+        if self.max_value_len.is_some_and(|max_len| value.len() > max_len) {
+            return Err(Error::ValueTooLarge);
+        }
+        let value_hash = self.hash_value(&value);
+        self.insert_trusted(key, value, value_hash)
+    }
+
+    /// Whether `key` is inside the range set by `with_key_range`, if any.
+    fn accepts_key(&self, key: Key) -> bool {
+        self.key_range.is_none_or(|(lo, hi)| (lo..=hi).contains(&key))
+    }
+
+    /// The hash a childless node holding `key` and `value` commits to in an
+    /// unsalted tree, for verifiers building expected commitments.
+    pub fn leaf_hash(key: &Key, value: &Value) -> HashType {
+        Node::compute_hash(key, Node::value_hash(NO_SALT, value), None, None)
+    }
+
+    /// Hashes `value` the way this tree does for its leaves, salt included.
+    /// The result can be passed to `insert_prehashed`.
+    pub fn hash_value(&self, value: &Value) -> HashType {
+        Node::value_hash(&self.salt, value)
+    }
+
+    /// Inserts `value` under `key` using a caller-supplied `value_hash`
+    /// instead of hashing the value again, for storing one large value under
+    /// many keys. The hash is trusted as is: if it isn't what `hash_value`
+    /// returns for `value`, the root commits to a value that isn't stored
+    /// and proofs for the entry won't verify.
+    pub fn insert_prehashed(
+        &mut self,
+        key: Key,
+        value: Value,
+        value_hash: HashType,
+    ) -> Result<(), Error> {
+        if self.max_value_len.is_some_and(|max_len| value.len() > max_len) {
+            return Err(Error::ValueTooLarge);
+        }
+        self.insert_trusted(key, value, value_hash).map(|_| ())
+    }
+
+    fn insert_trusted(
+        &mut self,
+        key: Key,
+        value: Value,
+        value_hash: HashType,
+    ) -> Result<InsertStats, Error> {
+        if !self.accepts_key(key) {
+            return Err(Error::KeyOutOfRange);
+        }
+        let sent = (!self.subscribers.0.is_empty()).then(|| value.clone());
+        if let Some(leaf_hashes) = &self.leaf_hashes {
+            let leaf_hash = Node::compute_hash(&key, value_hash, None, None);
+            if let Some((other_key, other_value)) = leaf_hashes.get(&leaf_hash) {
+                if (*other_key, other_value) != (key, &value) {
+                    return Err(Error::HashCollision);
+                }
+            }
+        }
+        let evicted = self.lru.as_ref().and_then(|lru| lru.borrow_mut().admit(key));
+        if let Some(evicted) = evicted {
+            self.delete(evicted).expect("LRU order only tracks live keys");
+        }
+        self.probe_path(key);
+        let rotations = self.insert_hashed(key, value, value_hash);
+        if let Some(value) = sent {
+            self.subscribers.send(Operation::Insert(key, value), self.root_or_empty());
+        }
+        Ok(InsertStats { rotations })
+    }
+
+    /// Streams every later `insert` and `delete` on this tree, in order, as
+    /// the operation and the root hash after it (`EMPTY_HASH` once the tree
+    /// is empty), so replaying the stream on a copy of the tree's current
+    /// state converges to the same roots. Inserts carry the value as given,
+    /// before any codec; LRU evictions arrive as deletes. Other mutations,
+    /// such as `soft_delete`, `map_values` or `drain_filter`, aren't
+    /// streamed, and a `rollback` or clone doesn't keep the subscriptions. A
+    /// subscriber whose receiver is dropped stops being sent to.
+    pub fn subscribe(&mut self) -> Receiver<(Operation, HashType)> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.0.push(sender);
+        receiver
+    }
+
+    fn root_or_empty(&self) -> HashType {
+        self.root.as_ref().map_or(EMPTY_HASH, |n| n.hash)
+    }
+
+    /// Returns the number of rotations inserting `key` would perform,
+    /// without touching the tree. Walks the insertion path bottom-up with
+    /// the heights the new leaf would produce; an AVL insert rebalances at
+    /// most once, and stops propagating as soon as a height is unchanged.
+    pub fn rotations_for_insert(&self, key: Key) -> usize {
+        let mut path = Vec::new();
+        let mut current = &self.root;
+        while let Some(n) = current {
+            let went_left = match key.cmp(&n.key) {
+                Ordering::Less => true,
+                Ordering::Greater => false,
+                Ordering::Equal => return 0,
+            };
+            path.push((n, went_left));
+            current = if went_left { &n.left } else { &n.right };
+        }
+        // Heights of the changed child's own children, for double rotations.
+        let mut child = (0, 0);
+        let mut child_height = 1;
+        for (n, went_left) in path.into_iter().rev() {
+            let (left, right) = if went_left {
+                (child_height, Node::height(&n.right))
+            } else {
+                (Node::height(&n.left), child_height)
+            };
+            let bf = right - left;
+            if bf > 1 {
+                return if child.1 - child.0 < 0 { 2 } else { 1 };
+            } else if bf < -1 {
+                return if child.1 - child.0 > 0 { 2 } else { 1 };
+            }
+            let height = 1 + left.max(right);
+            if height == n.height {
+                return 0;
+            }
+            child = (left, right);
+            child_height = height;
+        }
+        0
+    }
+
+    /// Inserts `value` as given, compressing it only to store it.
+    fn insert_hashed(&mut self, key: Key, value: Value, value_hash: HashType) -> usize {
+        let old = Node::find(&self.root, key);
+        let existed = old.is_some();
+        if old.is_some_and(|old| old.is_tombstone()) {
+            self.tombstones -= 1;
+        }
+        let old = old.filter(|old| !old.is_tombstone());
+        if let Some(index) = &mut self.value_index {
+            if let Some(old) = old {
+                index.remove(key, &ValueCodec::decode(self.codec.as_ref(), &old.value));
+            }
+            index.add(key, &value);
+        }
+        if let Some(leaf_hashes) = &mut self.leaf_hashes {
+            if let Some(old) = old {
+                leaf_hashes.remove(&Node::compute_hash(&key, old.value_hash, None, None));
+            }
+            let leaf_hash = Node::compute_hash(&key, value_hash, None, None);
+            leaf_hashes.insert(leaf_hash, (key, value.clone()));
+        }
+        if !existed {
+            self.len += 1;
+        }
+        let value = match &self.codec {
+            Some(codec) => (codec.compress)(&value),
+            None => value,
+        };
+        let mut rotations = 0;
+        self.root = Node::insert(self.root.take(), key, value, value_hash, &mut rotations);
+        rotations
+    }
+
+    /// Inserts and returns a membership proof for the entry, valid against
+    /// the new root.
+    pub fn insert_with_proof(&mut self, key: Key, value: Value) -> Result<ProofNode, Error> {
+        self.insert(key, value)?;
+        Node::generate_proof(&self.root, key).map(|proof| self.decompress_proof(proof))
+    }
+
+    /// Removes `key`. Fails with `Error::Deleted` if it is a `soft_delete`
+    /// tombstone, which only `compact` removes.
+    pub fn delete(&mut self, key: Key) -> Result<(), Error> {
+        if Node::find(&self.root, key).is_some_and(|n| n.is_tombstone()) {
+            return Err(Error::Deleted);
+        }
+        self.remove(key)
+    }
+
+    /// `delete` without the tombstone check, so `compact` can purge them.
+    fn remove(&mut self, key: Key) -> Result<(), Error> {
+        if self.root.is_none() {
+            return Err(Error::Empty);
+        }
+        self.probe_path(key);
+        let (new_root, deleted) = Node::delete(self.root.take(), key)?;
+        self.root = new_root;
+        if let Some(deleted) = deleted {
+            if deleted.is_tombstone() {
+                self.tombstones -= 1;
+            } else {
+                if let Some(index) = &mut self.value_index {
+                    index.remove(key, &ValueCodec::decode(self.codec.as_ref(), &deleted.value));
+                }
+                if let Some(leaf_hashes) = &mut self.leaf_hashes {
+                    let leaf_hash = Node::compute_hash(&key, deleted.value_hash, None, None);
+                    leaf_hashes.remove(&leaf_hash);
+                }
+            }
+            self.len -= 1;
+            if let Some(lru) = &self.lru {
+                lru.borrow_mut().remove(key);
+            }
+            self.deletes_since_rebuild += 1;
+            self.maybe_rebuild();
+            self.subscribers.send(Operation::Delete(key), self.root_or_empty());
+            Ok(())
+        } else {
+            Err(Error::NotFound)
+        }
+    }
+
+    /// Marks `key` deleted while keeping its node, so the deletion stays in
+    /// the commitment and can be proved with `verify_tombstone`. The node
+    /// drops its value and commits to a fixed tombstone hash instead; the
+    /// shape is unchanged. Afterwards `lookup` reports `Error::Deleted` and
+    /// iteration, `len` and the other accessors skip the key, though its
+    /// node stays until `compact`. Inserting the key again revives it.
+    pub fn soft_delete(&mut self, key: Key) -> Result<(), Error> {
+        if self.root.is_none() {
+            return Err(Error::Empty);
+        }
+        let n = Node::find(&self.root, key).ok_or(Error::NotFound)?;
+        if n.is_tombstone() {
+            return Err(Error::Deleted);
+        }
+        let value_hash = n.value_hash;
+        let value = Node::mark_tombstone(&mut self.root, key);
+        self.tombstones += 1;
+        if let Some(index) = &mut self.value_index {
+            index.remove(key, &ValueCodec::decode(self.codec.as_ref(), &value));
+        }
+        if let Some(leaf_hashes) = &mut self.leaf_hashes {
+            leaf_hashes.remove(&Node::compute_hash(&key, value_hash, None, None));
+        }
+        Ok(())
+    }
+
+    /// Exchanges the values of `a` and `b`, then rehashes the nodes on
+    /// their two search paths once each. Fails, leaving the tree unchanged,
+    /// with `Error::NotFound` if either key is absent and `Error::Deleted`
+    /// if either is a `soft_delete` tombstone.
+    pub fn swap_values(&mut self, a: Key, b: Key) -> Result<(), Error> {
+        if self.root.is_none() {
+            return Err(Error::Empty);
+        }
+        let (Some(node_a), Some(node_b)) = (Node::find(&self.root, a), Node::find(&self.root, b))
+        else {
+            return Err(Error::NotFound);
+        };
+        if node_a.is_tombstone() || node_b.is_tombstone() {
+            return Err(Error::Deleted);
+        }
+        if a == b {
+            return Ok(());
+        }
+        let n = Node::find_mut(&mut self.root, a).expect("a is in the tree");
+        let (value, value_hash) = (std::mem::take(&mut n.value), n.value_hash);
+        let n = Node::find_mut(&mut self.root, b).expect("b is in the tree");
+        let value = std::mem::replace(&mut n.value, value);
+        let value_hash = std::mem::replace(&mut n.value_hash, value_hash);
+        let n = Node::find_mut(&mut self.root, a).expect("a is in the tree");
+        (n.value, n.value_hash) = (value, value_hash);
+        Node::rehash_paths(&mut self.root, a, b);
+
+        let (now_a, now_b) = (Node::find(&self.root, a), Node::find(&self.root, b));
+        let (now_a, now_b) = (now_a.expect("a is in the tree"), now_b.expect("b is in the tree"));
+        let value_a = ValueCodec::decode(self.codec.as_ref(), &now_a.value);
+        let value_b = ValueCodec::decode(self.codec.as_ref(), &now_b.value);
+        if let Some(index) = &mut self.value_index {
+            index.remove(a, &value_b);
+            index.remove(b, &value_a);
+            index.add(a, &value_a);
+            index.add(b, &value_b);
+        }
+        if let Some(leaf_hashes) = &mut self.leaf_hashes {
+            let swapped = [(a, now_b, now_a, &value_a), (b, now_a, now_b, &value_b)];
+            for (key, old, new, value) in swapped {
+                leaf_hashes.remove(&Node::compute_hash(&key, old.value_hash, None, None));
+                let leaf_hash = Node::compute_hash(&key, new.value_hash, None, None);
+                leaf_hashes.insert(leaf_hash, (key, value.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes every `soft_delete` tombstone for real, returning how many.
+    pub fn compact(&mut self) -> usize {
+        let mut tombstones = Vec::new();
+        Node::visit_range(&self.root, Key::MIN, Key::MAX, &mut |n| {
+            if n.is_tombstone() {
+                tombstones.push(n.key);
+            }
+        });
+        for &key in &tombstones {
+            self.remove(key).expect("tombstone is in the tree");
+        }
+        tombstones.len()
+    }
+
+    /// Rebuilds into the canonical shape if enough deletes have built up
+    /// under `with_rebuild_threshold`. Returns whether it rebuilt.
+    pub fn maybe_rebuild(&mut self) -> bool {
+        let due = self.rebuild_threshold.is_some_and(|fraction| {
+            self.deletes_since_rebuild as f64 > fraction * self.len as f64
+        });
+        if !due {
+            return false;
+        }
+        let mut entries = Vec::with_capacity(self.len);
+        Node::drain_sorted(self.root.take(), &mut entries);
+        let mut previous = None;
+        self.root = Node::build_sorted(&mut entries.into_iter(), &mut previous, self.len);
+        self.deletes_since_rebuild = 0;
+        self.rebuilds += 1;
+        true
+    }
+
+    /// How many times `maybe_rebuild` has rebuilt the tree.
+    pub fn rebuild_count(&self) -> usize {
+        self.rebuilds
+    }
+
+    /// Removes every entry `pred` matches and returns them in key order.
+    /// The survivors are rebuilt once into the canonical `from_sorted` shape
+    /// rather than deleted one by one; a tree with no matches is untouched.
+    pub fn drain_filter(
+        &mut self,
+        mut pred: impl FnMut(&Key, &Value) -> bool,
+    ) -> Vec<(Key, Value)> {
+        let matched: HashSet<Key> = self
+            .iter()
+            .filter(|(key, value)| pred(key, value))
+            .map(|(key, _)| *key)
+            .collect();
+        if matched.is_empty() {
+            return Vec::new();
+        }
+        let mut entries = Vec::with_capacity(self.len);
+        Node::drain_sorted(self.root.take(), &mut entries);
+        let (drained, kept): (Vec<_>, Vec<_>) =
+            entries.into_iter().partition(|(key, _, _)| matched.contains(key));
+        let drained: Vec<_> = drained
+            .into_iter()
+            .map(|(key, value, value_hash)| (key, Self::decoded(&self.codec, value), value_hash))
+            .collect();
+        for (key, value, value_hash) in &drained {
+            if let Some(lru) = &self.lru {
+                lru.borrow_mut().remove(*key);
+            }
+            if let Some(index) = &mut self.value_index {
+                index.remove(*key, value);
+            }
+            if let Some(leaf_hashes) = &mut self.leaf_hashes {
+                leaf_hashes.remove(&Node::compute_hash(key, *value_hash, None, None));
+            }
+        }
+        self.len = kept.len();
+        let mut previous = None;
+        self.root = Node::build_sorted(&mut kept.into_iter(), &mut previous, self.len);
+        self.deletes_since_rebuild = 0;
+        drained.into_iter().map(|(key, value, _)| (key, value)).collect()
+    }
+
+    /// Removes all but the `n` largest keys, such as the latest `n` entries
+    /// of a log keyed by ascending sequence numbers, and returns how many
+    /// were removed. The cutoff is the key of rank `len - n`; everything
+    /// below it goes in a single `drain_filter` rebuild.
+    pub fn keep_highest(&mut self, n: usize) -> usize {
+        let removed = self.len().saturating_sub(n);
+        if removed == 0 {
+            return 0;
+        }
+        let cutoff = self.iter().nth(removed).map(|(key, _)| *key);
+        self.drain_filter(|key, _| cutoff.is_none_or(|cutoff| *key < cutoff)).len()
+    }
+
+    /// The value under `key`, decompressed under `with_value_codec` and
+    /// borrowed from the tree otherwise.
+    pub fn lookup(&self, key: Key) -> Result<Cow<'_, Value>, Error> {
+        if self.root.is_none() {
+            return Err(Error::Empty);
+        }
+        self.probe_path(key);
+        let value = Node::lookup(&self.root, key)?;
+        if let Some(lru) = &self.lru {
+            lru.borrow_mut().touch(key);
+        }
+        Ok(self.decode(value))
+    }
+
+    /// Whether inserting `(key, value)` would change the tree: the key is
+    /// absent, soft-deleted or holds a different value.
+    pub fn would_change(&self, key: Key, value: &Value) -> bool {
+        Node::find(&self.root, key)
+            .is_none_or(|n| n.is_tombstone() || *self.decode(&n.value) != *value)
+    }
+
+    /// A stored value as it was inserted; see `with_value_codec`.
+    fn decode<'a>(&self, value: &'a Value) -> Cow<'a, Value> {
+        ValueCodec::decode(self.codec.as_ref(), value)
+    }
+
+    /// Like `decode`, for a value moved out of the tree.
+    fn decoded(codec: &Option<ValueCodec>, value: Value) -> Value {
+        match codec {
+            Some(codec) => (codec.decompress)(&value),
+            None => value,
+        }
+    }
+
+    /// Starts a read transaction borrowing the tree until it is dropped.
+    pub fn read_tx(&self) -> ReadTx<'_> {
+        ReadTx { tree: self }
+    }
+
+    /// Looks up every key, returning results in the same order as `keys`.
+    pub fn lookup_many<'a>(&'a self, keys: &[Key]) -> Vec<Result<Cow<'a, Value>, Error>> {
+        keys.iter().map(|&key| self.lookup(key)).collect()
+    }
+
+    /// Gives up mutation, returning a read-only view of this tree.
+    pub fn freeze(self) -> FrozenTree {
+        FrozenTree { tree: self }
+    }
+
+    /// Captures the current state. Nodes aren't shared between trees, so
+    /// this clones the whole tree.
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint { tree: self.clone() }
+    }
+
+    /// Restores the tree exactly as it was when `savepoint` was taken.
+    pub fn rollback(&mut self, savepoint: Savepoint) {
+        *self = savepoint.tree;
+    }
+
+    /// Iterates the entries in ascending key order.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter::new(&self.root, self.codec.as_ref())
+    }
+
+    /// The entries at ranks `i..j` in key order, with `j` clamped to `len`.
+    /// Walks the tree in order, so this is O(j).
+    pub fn select_range(&self, i: usize, j: usize) -> Vec<(&Key, Cow<'_, Value>)> {
+        let j = j.min(self.len());
+        self.iter().skip(i).take(j.saturating_sub(i)).collect()
+    }
+
+    /// The entry with the smallest key.
+    pub fn min(&self) -> Option<(&Key, Cow<'_, Value>)> {
+        self.iter().next()
+    }
+
+    /// The entry with the largest key.
+    pub fn max(&self) -> Option<(&Key, Cow<'_, Value>)> {
+        self.range_iter_rev(Key::MIN, Key::MAX).next()
+    }
+
+    /// The entry at rank 1, the successor of the minimum.
+    pub fn second_min(&self) -> Option<(&Key, Cow<'_, Value>)> {
+        self.iter().nth(1)
+    }
+
+    /// The entry at rank `len - 2`, the predecessor of the maximum.
+    pub fn second_max(&self) -> Option<(&Key, Cow<'_, Value>)> {
+        self.range_iter_rev(Key::MIN, Key::MAX).nth(1)
+    }
+
+    /// The `(lower, upper)` pairs of adjacent keys, in ascending order, with
+    /// `upper - lower > min_gap`. A difference too wide for `Key` counts as
+    /// exceeding any `min_gap`.
+    pub fn gaps(&self, min_gap: Key) -> Vec<(Key, Key)> {
+        let mut gaps = Vec::new();
+        let mut previous: Option<Key> = None;
+        for (&key, _) in self.iter() {
+            if let Some(lower) = previous {
+                if key.checked_sub(lower).is_none_or(|gap| gap > min_gap) {
+                    gaps.push((lower, key));
+                }
+            }
+            previous = Some(key);
+        }
+        gaps
+    }
+
+    /// Summarizes the gaps between consecutive keys in one in-order pass,
+    /// keeping a running mean and variance (Welford's method).
+    pub fn key_spacing_stats(&self) -> SpacingStats {
+        let mut stats = SpacingStats::default();
+        let mut squares = 0.0;
+        let mut keys = self.iter().map(|(&key, _)| i64::from(key));
+        let Some(mut previous) = keys.next() else {
+            return stats;
+        };
+        for key in keys {
+            let gap = (key - previous) as u64;
+            previous = key;
+            stats.min = if stats.gaps == 0 { gap } else { stats.min.min(gap) };
+            stats.max = stats.max.max(gap);
+            stats.gaps += 1;
+            let delta = gap as f64 - stats.mean;
+            stats.mean += delta / stats.gaps as f64;
+            squares += delta * (gap as f64 - stats.mean);
+        }
+        if stats.gaps > 0 {
+            stats.stddev = (squares / stats.gaps as f64).sqrt();
+        }
+        stats
+    }
+
+    /// Counts values by byte length. `buckets` are ascending inclusive upper
+    /// bounds: count `i` is of values longer than `buckets[i - 1]` and at
+    /// most `buckets[i]` bytes, and one last count holds the values longer
+    /// than every bound.
+    pub fn value_size_histogram(&self, buckets: &[usize]) -> Vec<usize> {
+        debug_assert!(buckets.windows(2).all(|pair| pair[0] < pair[1]), "buckets must ascend");
+        let mut counts = vec![0; buckets.len() + 1];
+        for (_, value) in self.iter() {
+            counts[buckets.partition_point(|&bound| bound < value.len())] += 1;
+        }
+        counts
+    }
+
+    /// The middle entry in key order, or the lower of the two middle entries
+    /// when `len` is even: rank `(len - 1) / 2`, which is `len / 2` for odd
+    /// sizes. Nodes carry no subtree sizes, so this walks to the midpoint.
+    pub fn median(&self) -> Option<(&Key, Cow<'_, Value>)> {
+        self.iter().nth(self.len().checked_sub(1)? / 2)
+    }
+
+    /// Deletes the entries at ranks `i..j`, clamped to `len`, returning them
+    /// in key order.
+    pub fn delete_rank_range(&mut self, i: usize, j: usize) -> Vec<(Key, Value)> {
+        let doomed: Vec<(Key, Value)> = self
+            .select_range(i, j)
+            .into_iter()
+            .map(|(key, value)| (*key, value.into_owned()))
+            .collect();
+        for (key, _) in &doomed {
+            self.delete(*key).expect("selected keys are present");
+        }
+        doomed
+    }
+
+    /// Iterates the entries with keys in `[lo, hi]` in ascending order,
+    /// skipping subtrees outside the range.
+    pub fn range_iter(&self, lo: Key, hi: Key) -> impl Iterator<Item = (&Key, Cow<'_, Value>)> {
+        RangeIter::new(&self.root, self.codec.as_ref(), lo, hi, false)
+    }
+
+    /// Keyset pagination: up to `limit` entries with keys strictly greater
+    /// than `after` (from the start for `None`), and the key to pass as
+    /// `after` for the next page, or `None` once nothing follows.
+    pub fn page(&self, after: Option<Key>, limit: usize) -> (Vec<(Key, Value)>, Option<Key>) {
+        let lo = match after {
+            Some(after) => match after.checked_add(1) {
+                Some(lo) => lo,
+                None => return (Vec::new(), None),
+            },
+            None => Key::MIN,
+        };
+        let mut entries = self.range_iter(lo, Key::MAX).peekable();
+        let page: Vec<(Key, Value)> = entries
+            .by_ref()
+            .take(limit)
+            .map(|(key, value)| (*key, value.into_owned()))
+            .collect();
+        let next = match (page.last(), entries.peek()) {
+            (Some((last, _)), Some(_)) => Some(*last),
+            _ => None,
+        };
+        (page, next)
+    }
+
+    /// Like `range_iter`, keeping only entries whose value satisfies `pred`.
+    pub fn query(
+        &self,
+        lo: Key,
+        hi: Key,
+        pred: impl Fn(&Value) -> bool,
+    ) -> impl Iterator<Item = (&Key, Cow<'_, Value>)> {
+        self.range_iter(lo, hi).filter(move |(_, value)| pred(value))
+    }
+
+    /// Like `range_iter`, but in descending key order.
+    pub fn range_iter_rev(
+        &self,
+        lo: Key,
+        hi: Key,
+    ) -> impl Iterator<Item = (&Key, Cow<'_, Value>)> {
+        RangeIter::new(&self.root, self.codec.as_ref(), lo, hi, true)
+    }
+
+    /// Iterates the entries in key order, generating each entry's proof only
+    /// when it is pulled.
+    pub fn lazy_proof_iter(&self) -> impl Iterator<Item = (Key, Value, ProofNode)> + '_ {
+        self.iter().map(move |(key, value)| {
+            let proof = self.generate_proof(*key).expect("iterated keys are present");
+            (*key, value.into_owned(), proof)
+        })
+    }
+
+    /// Walks both trees in key order at once, classifying every key by which
+    /// side holds it.
+    pub fn merge_join<'a>(
+        &'a self,
+        other: &'a MerkleAvlTree,
+    ) -> impl Iterator<Item = MergeItem<'a>> {
+        let mut left = self.iter().peekable();
+        let mut right = other.iter().peekable();
+        std::iter::from_fn(move || match (left.peek(), right.peek()) {
+            (Some((lk, _)), Some((rk, _))) => match lk.cmp(rk) {
+                Ordering::Less => left.next().map(|(k, v)| MergeItem::OnlyLeft(k, v)),
+                Ordering::Greater => right.next().map(|(k, v)| MergeItem::OnlyRight(k, v)),
+                Ordering::Equal => {
+                    let (k, lv) = left.next()?;
+                    let (_, rv) = right.next()?;
+                    Some(MergeItem::Both(k, lv, rv))
+                }
+            },
+            (Some(_), None) => left.next().map(|(k, v)| MergeItem::OnlyLeft(k, v)),
+            (None, Some(_)) => right.next().map(|(k, v)| MergeItem::OnlyRight(k, v)),
+            (None, None) => None,
+        })
+    }
+
+    /// The patch from this tree's entries to `target`'s, found by a
+    /// `merge_join` of the two.
+    pub fn make_patch(&self, target: &MerkleAvlTree) -> Patch {
+        let ops = self
+            .merge_join(target)
+            .filter_map(|item| match item {
+                MergeItem::OnlyLeft(&key, _) => Some(PatchOp::Delete(key)),
+                MergeItem::OnlyRight(&key, value) => Some(PatchOp::Insert(key, value.into_owned())),
+                MergeItem::Both(&key, old, new) if old != new => {
+                    Some(PatchOp::Update(key, new.into_owned()))
+                }
+                MergeItem::Both(..) => None,
+            })
+            .collect();
+        Patch { ops }
+    }
+
+    /// Applies `patch` in order. Afterwards the tree holds exactly the
+    /// target's entries. Its root equals the target's when the two end up
+    /// the same shape: always if the patch only updates, since updates
+    /// don't rotate, and whenever the target was itself produced from this
+    /// tree by the same operations in key order. Every operation's
+    /// precondition is checked before any is applied, failing with
+    /// `Error::PatchConflict`, with `soft_delete` tombstones counting as
+    /// absent; an insert the tree itself refuses, such as an oversized
+    /// value, leaves the earlier operations applied.
+    pub fn apply_patch(&mut self, patch: &Patch) -> Result<(), Error> {
+        let applies = patch.ops.iter().all(|op| match op {
+            PatchOp::Insert(key, _) => Node::find_live(&self.root, *key).is_none(),
+            PatchOp::Delete(key) | PatchOp::Update(key, _) => {
+                Node::find_live(&self.root, *key).is_some()
+            }
+        });
+        if !applies {
+            return Err(Error::PatchConflict);
+        }
+        for op in &patch.ops {
+            match op {
+                PatchOp::Insert(key, value) | PatchOp::Update(key, value) => {
+                    self.insert(*key, value.clone())?
+                }
+                PatchOp::Delete(key) => self.delete(*key)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn root_hash(&self) -> Option<RootHash> {
+        self.root.as_ref().map(|node| RootHash(node.hash))
+    }
+
+    /// The root hash as a fixed-width hex string of `2 * HASH_BYTES`
+    /// digits: 16 by default, 32 under `hash-128` and 64 under `hash-256`.
+    pub fn root_hex(&self) -> Option<String> {
+        self.root_hash().map(|root| root.to_string())
+    }
+
+    /// Number of entries, not counting `soft_delete` tombstones.
+    pub fn len(&self) -> usize {
+        self.len - self.tombstones
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of nodes on the longest root-to-leaf path; 0 when empty.
+    pub fn height(&self) -> i32 {
+        Node::height(&self.root)
+    }
+
+    /// Height divided by the least height any binary tree with `len` nodes
+    /// can have, `ceil(log2(len + 1))`. A perfectly balanced tree scores
+    /// 1.0; AVL trees stay below about 1.44. An empty tree scores 1.0.
+    pub fn balance_score(&self) -> f64 {
+        if self.len == 0 {
+            return 1.0;
+        }
+        let min_height = usize::BITS - self.len.leading_zeros();
+        f64::from(self.height()) / f64::from(min_height)
+    }
+
+    /// The keys along one of the longest root-to-leaf paths, root first.
+    pub fn deepest_path(&self) -> Vec<Key> {
+        let mut path = Vec::new();
+        let mut current = &self.root;
+        while let Some(n) = current {
+            path.push(n.key);
+            current = if Node::height(&n.left) >= Node::height(&n.right) {
+                &n.left
+            } else {
+                &n.right
+            };
+        }
+        path
+    }
+
+    /// Commits to the entries with keys in `[lo, hi]`, independent of how the
+    /// rest of the tree is shaped: their leaf hashes are hashed in key order
+    /// under a range domain tag.
+    pub fn range_root(&self, lo: Key, hi: Key) -> HashType {
+        let mut hasher = WideHasher::new();
+        RANGE_DOMAIN.hash(&mut hasher);
+        Node::visit_range(&self.root, lo, hi, &mut |n| {
+            Node::compute_hash(&n.key, n.value_hash, None, None).hash(&mut hasher);
+        });
+        hasher.finish_wide()
+    }
+
+    /// Recomputes every value hash and node hash from the stored values,
+    /// decompressed under `with_value_codec`, and checks each against what
+    /// the node holds.
+    pub fn verify_hashes(&self) -> bool {
+        Node::hashes_ok(&self.root, &self.salt, self.codec.as_ref())
+    }
+
+    /// `verify_hashes`, with subtrees near the root checked on separate
+    /// threads, about one per available core. A value codec isn't shared
+    /// across threads, so a tree with one is checked on this thread alone.
+    #[cfg(feature = "parallel")]
+    pub fn verify_hashes_parallel(&self) -> bool {
+        if self.codec.is_some() {
+            return self.verify_hashes();
+        }
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let depth = threads.next_power_of_two().trailing_zeros();
+        Node::hashes_ok_parallel(&self.root, &self.salt, depth)
+    }
+
+    /// Finds the deepest node breaking an ordering, height, balance or hash
+    /// invariant. Children are checked before their parent, so a corrupted
+    /// node is reported rather than the ancestors it throws off.
+    pub fn find_invariant_violation(&self) -> Option<Violation> {
+        Node::find_violation(&self.root, None, None)
+    }
+
+    /// The tree's keys, heights and links, `None` for an empty tree.
+    pub fn skeleton(&self) -> Option<Skeleton> {
+        Node::skeleton(&self.root).map(|skeleton| *skeleton)
+    }
+
+    /// Every entry with its depth, the root at 0, breadth-first: level by
+    /// level, each level in key order. `soft_delete` tombstones are left out
+    /// but still count towards their descendants' depths.
+    pub fn level_order(&self) -> Vec<(usize, Key, Value)> {
+        let mut entries = Vec::with_capacity(self.len());
+        let mut queue: VecDeque<(usize, &Node)> =
+            self.root.as_deref().map(|n| (0, n)).into_iter().collect();
+        while let Some((depth, n)) = queue.pop_front() {
+            if !n.is_tombstone() {
+                entries.push((depth, n.key, self.decode(&n.value).into_owned()));
+            }
+            queue.extend(n.left.as_deref().map(|left| (depth + 1, left)));
+            queue.extend(n.right.as_deref().map(|right| (depth + 1, right)));
+        }
+        entries
+    }
+
+    /// Gathers the tree's diagnostics into one report.
+    pub fn audit_report(&self) -> AuditReport {
+        AuditReport {
+            len: self.len(),
+            height: self.height(),
+            balance_score: self.balance_score(),
+            root_hash: self.root_hash(),
+            min_key: self.min().map(|(&key, _)| key),
+            max_key: self.max().map(|(&key, _)| key),
+            violation: self.find_invariant_violation(),
+        }
+    }
+
+    /// Approximate heap footprint: one `Node` per entry plus each value's
+    /// allocated capacity.
+    pub fn memory_usage(&self) -> usize {
+        Node::memory_usage(&self.root)
+    }
+
+    /// Releases unused capacity held by every value. Contents, and so the
+    /// root hash, are untouched.
+    pub fn shrink_values(&mut self) {
+        Node::shrink_values(&mut self.root);
+    }
+
+    /// Replaces every value in place with `f`, visiting keys in post-order,
+    /// and rehashes the tree once on the way back up. Keys and shape are
+    /// unchanged, so the root equals that of a fresh tree holding the mapped
+    /// values in the same shape. Under `with_collision_check` the leaf hash
+    /// map is rebuilt afterwards; a collision introduced by `f` can't be
+    /// refused at that point, and the map keeps the later of the two keys.
+    pub fn map_values(&mut self, mut f: impl FnMut(&Key, &mut Value)) {
+        Node::map_values(
+            &mut self.root,
+            &self.salt,
+            self.codec.as_ref(),
+            &mut f,
+            &mut self.value_index,
+        );
+        if let Some(leaf_hashes) = &mut self.leaf_hashes {
+            leaf_hashes.clear();
+            let mut stack: Vec<&Node> = self.root.as_deref().into_iter().collect();
+            while let Some(n) = stack.pop() {
+                let leaf_hash = Node::compute_hash(&n.key, n.value_hash, None, None);
+                let value = ValueCodec::decode(self.codec.as_ref(), &n.value).into_owned();
+                leaf_hashes.insert(leaf_hash, (n.key, value));
+                stack.extend(n.left.as_deref());
+                stack.extend(n.right.as_deref());
+            }
+        }
+    }
+
+    /// Returns the deepest node that has both `a` and `b` in its subtree,
+    /// counting a node as its own ancestor. `None` if either key is absent
+    /// or soft-deleted, or if that node is itself a `soft_delete` tombstone.
+    pub fn lca(&self, a: Key, b: Key) -> Option<(&Key, Cow<'_, Value>)> {
+        Node::find_live(&self.root, a)?;
+        Node::find_live(&self.root, b)?;
+        let mut current = self.root.as_ref();
+        while let Some(n) = current {
+            if a < n.key && b < n.key {
+                current = n.left.as_ref();
+            } else if a > n.key && b > n.key {
+                current = n.right.as_ref();
+            } else {
+                return (!n.is_tombstone()).then(|| (&n.key, self.decode(&n.value)));
+            }
+        }
+        None
+    }
+
+    /// Whether `a` and `b` both fall in the key range owned by the subtree
+    /// rooted at `ancestor`, i.e. they do or would live beneath it. `None` if
+    /// `ancestor` isn't in the tree.
+    pub fn same_subtree(&self, a: Key, b: Key, ancestor: Key) -> Option<bool> {
+        let mut lower = None;
+        let mut upper = None;
+        let mut current = &self.root;
+        while let Some(n) = current {
+            match ancestor.cmp(&n.key) {
+                Ordering::Less => {
+                    upper = Some(n.key);
+                    current = &n.left;
+                }
+                Ordering::Greater => {
+                    lower = Some(n.key);
+                    current = &n.right;
+                }
+                Ordering::Equal => {
+                    let within = |key: Key| {
+                        lower.is_none_or(|lower| key > lower)
+                            && upper.is_none_or(|upper| key < upper)
+                    };
+                    return Some(within(a) && within(b));
+                }
+            }
+        }
+        None
+    }
+
+    /// The hash of the subtree rooted at the node holding `subtree_key`,
+    /// which `subtree_proofs` verify against.
+    pub fn subtree_hash(&self, subtree_key: Key) -> Result<RootHash, Error> {
+        Node::find(&self.root, subtree_key)
+            .map(|n| RootHash(n.hash))
+            .ok_or(Error::NotFound)
+    }
+
+    /// Localises where a remote replica departs from this tree by asking it
+    /// for subtree hashes, keyed by the key at the subtree's root, top-down.
+    /// `None` if the roots agree. Otherwise descends while exactly one
+    /// child's subtree differs, and returns the key of the node where that
+    /// stops: a node whose own entry differs, or where the difference
+    /// spreads over both sides. A remote with no node at a key counts as
+    /// differing there. Costs at most two requests per level.
+    pub fn divergence_point(
+        &self,
+        get_remote_subtree_hash: impl Fn(Key) -> Option<HashType>,
+    ) -> Option<Key> {
+        let differs = |n: &Node| get_remote_subtree_hash(n.key) != Some(n.hash);
+        let mut current = self.root.as_deref().filter(|n| differs(n))?;
+        loop {
+            let mut differing = [&current.left, &current.right]
+                .into_iter()
+                .filter_map(|child| child.as_deref())
+                .filter(|n| differs(n));
+            match (differing.next(), differing.next()) {
+                (Some(child), None) => current = child,
+                _ => return Some(current.key),
+            }
+        }
+    }
+
+    /// Every live entry under the node holding `subtree_key`, in key order, with
+    /// a proof relative to that node, so a shard can be checked against
+    /// `subtree_hash(subtree_key)` alone.
+    pub fn subtree_proofs(&self, subtree_key: Key) -> Result<Vec<(Key, Value, ProofNode)>, Error> {
+        let subtree = Node::find_link(&self.root, subtree_key);
+        if subtree.is_none() {
+            return Err(Error::NotFound);
+        }
+        let mut entries = Vec::new();
+        Node::visit_range(subtree, Key::MIN, Key::MAX, &mut |n| {
+            if !n.is_tombstone() {
+                entries.push((n.key, self.decode(&n.value).into_owned()))
+            }
+        });
+        entries
+            .into_iter()
+            .map(|(key, value)| {
+                let proof = self.decompress_proof(Node::generate_proof(subtree, key)?);
+                Ok((key, value, proof))
+            })
+            .collect()
+    }
+
+    /// The multiproof frontier for `keys`: the hash of every maximal subtree
+    /// hanging off the union of their search paths, keyed by the subtree's
+    /// root key, in key order. Each appears once however many paths it
+    /// borders. With the nodes on the paths themselves, from
+    /// `frontier_path_nodes`, `root_from_frontier` rebuilds the root.
+    /// Fails with `Error::NotFound` if any key is absent.
+    pub fn proof_frontier(&self, keys: &[Key]) -> Result<Vec<(Key, HashType)>, Error> {
+        let mut frontier = Vec::new();
+        self.frontier(keys, &mut Vec::new(), &mut frontier)?;
+        Ok(frontier)
+    }
+
+    /// Every node on the union of the search paths for `keys`, as its key
+    /// and value hash, in pre-order; see `proof_frontier`.
+    pub fn frontier_path_nodes(&self, keys: &[Key]) -> Result<Vec<(Key, HashType)>, Error> {
+        let mut path_nodes = Vec::new();
+        self.frontier(keys, &mut path_nodes, &mut Vec::new())?;
+        Ok(path_nodes)
+    }
+
+    /// Rebuilds the root from the pre-order `path_nodes` and the `frontier`
+    /// subtrees hanging off them. A verifier that checks its entries' value
+    /// hashes appear in `path_nodes` and gets back the trusted root has
+    /// proved all of them at once. A missing frontier subtree reads as an
+    /// empty child and so gives a different root. `None` if the pieces don't
+    /// fit together: a path node is out of order or a frontier subtree has no
+    /// place to hang.
+    pub fn root_from_frontier(
+        path_nodes: &[(Key, HashType)],
+        frontier: &[(Key, HashType)],
+    ) -> Option<RootHash> {
+        fn build(
+            path_nodes: &mut std::iter::Peekable<std::slice::Iter<'_, (Key, HashType)>>,
+            frontier: &BTreeMap<Key, HashType>,
+            used: &mut usize,
+            lower: Option<Key>,
+            upper: Option<Key>,
+        ) -> Option<HashType> {
+            let within = |key: Key| {
+                lower.is_none_or(|lower| key > lower) && upper.is_none_or(|upper| key < upper)
+            };
+            if let Some(&(key, value_hash)) = path_nodes.next_if(|(key, _)| within(*key)) {
+                let left = build(path_nodes, frontier, used, lower, Some(key));
+                let right = build(path_nodes, frontier, used, Some(key), upper);
+                return Some(Node::compute_hash(&key, value_hash, left, right));
+            }
+            let (_, &hash) = frontier.iter().find(|(&key, _)| within(key))?;
+            *used += 1;
+            Some(hash)
+        }
+        let frontier_map: BTreeMap<Key, HashType> = frontier.iter().copied().collect();
+        let mut path = path_nodes.iter().peekable();
+        let mut used = 0;
+        let root = build(&mut path, &frontier_map, &mut used, None, None);
+        let fits = path.next().is_none() && used == frontier.len() && frontier_map.len() == used;
+        if fits {
+            root.map(RootHash)
+        } else {
+            None
+        }
+    }
+
+    /// Collects the pre-order path nodes and the frontier for `keys`.
+    fn frontier(
+        &self,
+        keys: &[Key],
+        path_nodes: &mut Vec<(Key, HashType)>,
+        frontier: &mut Vec<(Key, HashType)>,
+    ) -> Result<(), Error> {
+        if self.root.is_none() {
+            return Err(Error::Empty);
+        }
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        keys.dedup();
+        if keys.iter().any(|&key| Node::find(&self.root, key).is_none()) {
+            return Err(Error::NotFound);
+        }
+        Node::frontier(&self.root, &keys, path_nodes, frontier);
+        Ok(())
+    }
+
+    /// A bloom filter over every key, for peers to rule keys out without a
+    /// proof. The first byte holds `hashes`, the rest `bits` bits rounded up
+    /// to whole bytes; query it with `bloom_might_contain`.
+    pub fn bloom_filter(&self, bits: usize, hashes: usize) -> Vec<u8> {
+        assert!(bits > 0, "bloom filter needs at least one bit");
+        let hashes = u8::try_from(hashes).expect("at most 255 bloom hashes");
+        let mut filter = vec![0; 1 + bits.div_ceil(8)];
+        filter[0] = hashes;
+        for (key, _) in self.iter() {
+            for bit in Self::bloom_bits(&filter, *key) {
+                filter[1 + bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    /// False only if `key` is certainly not in the tree `filter` was built
+    /// from; true for every key that is, and for some that aren't.
+    pub fn bloom_might_contain(filter: &[u8], key: Key) -> bool {
+        filter.len() > 1
+            && Self::bloom_bits(filter, key)
+                .all(|bit| filter[1 + bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// The filter bits for `key`, by double hashing.
+    fn bloom_bits(filter: &[u8], key: Key) -> impl Iterator<Item = usize> {
+        let bits = (filter.len() - 1) as u64 * 8;
+        let seeded = |seed: u8| {
+            let mut hasher = DefaultHasher::new();
+            (seed, key).hash(&mut hasher);
+            hasher.finish()
+        };
+        let (h1, h2) = (seeded(0), seeded(1) | 1);
+        (0..u64::from(filter[0]))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+
+    /// Returns `(only_in_self, only_in_other)`, each in ascending order.
+    /// Values are ignored and `soft_delete` tombstones count as absent.
+    /// Subtrees whose hash also appears anywhere in the other tree hold
+    /// identical entries there and are skipped whole.
+    pub fn key_diff(&self, other: &MerkleAvlTree) -> (Vec<Key>, Vec<Key>) {
+        let one_side = |from: &MerkleAvlTree, against: &MerkleAvlTree| {
+            let mut shared = HashSet::new();
+            Node::collect_hashes(&against.root, &mut shared);
+            let mut missing = Vec::new();
+            Node::keys_missing_from(&from.root, &shared, &against.root, &mut missing);
+            missing
+        };
+        (one_side(self, other), one_side(other, self))
+    }
+
+    /// Whether every entry of this tree is in `other` with the same value,
+    /// `soft_delete` tombstones counting as absent on both sides. Unless
+    /// either tree holds tombstones, subtrees whose hash appears anywhere in
+    /// `other` are skipped whole. The walk stops at the first entry `other`
+    /// lacks.
+    pub fn is_subset_of(&self, other: &MerkleAvlTree) -> bool {
+        let mut shared = HashSet::new();
+        if self.tombstones == 0 && other.tombstones == 0 {
+            Node::collect_hashes(&other.root, &mut shared);
+        }
+        Node::contained_in(&self.root, self.codec.as_ref(), &shared, other)
+    }
+
+    /// How many entries differ from `old`: keys added, keys removed and
+    /// keys whose value hash changed, so both trees should share a salt.
+    /// Only live entries count: a `soft_delete` tombstone is an absent key.
+    /// Like `key_diff`, skips subtrees whose hash appears in the other tree.
+    pub fn change_count_since(&self, old: &MerkleAvlTree) -> usize {
+        let (mut ours, mut theirs) = (HashSet::new(), HashSet::new());
+        Node::collect_hashes(&self.root, &mut ours);
+        Node::collect_hashes(&old.root, &mut theirs);
+        Node::count_changes(&self.root, &theirs, &old.root, true)
+            + Node::count_changes(&old.root, &ours, &self.root, false)
+    }
+
+    pub fn generate_proof(&self, key: Key) -> Result<ProofNode, Error> {
+        if self.root.is_none() {
+            return Err(Error::Empty);
+        }
+        let Some(cache) = &self.proof_cache else {
+            return Self::compute_proof(&self.root, key).map(|proof| self.decompress_proof(proof));
+        };
+        let root = self.root.as_ref().map(|n| n.hash);
+        let mut cache = cache.borrow_mut();
+        if let Some(proof) = cache.get(key, root) {
+            return Ok(proof);
+        }
+        let proof = self.decompress_proof(Self::compute_proof(&self.root, key)?);
+        cache.put(key, proof.clone());
+        Ok(proof)
+    }
+
+    /// Like `generate_proof`, but a present key's value is replaced by its
+    /// committed hash, so the proof shows the key is in the tree without
+    /// revealing what it maps to. Check it with `verify_proof_hidden`.
+    pub fn generate_proof_hidden(&self, key: Key) -> Result<ProofNode, Error> {
+        let mut proof = self.generate_proof(key)?;
+        let Some(value_hash) = Node::find(&self.root, key).map(|n| n.value_hash) else {
+            return Ok(proof);
+        };
+        let mut level = &mut proof;
+        loop {
+            match level {
+                ProofNode::Left(_, _, child, _) | ProofNode::Right(_, _, _, child) => {
+                    level = &mut **child
+                }
+                ProofNode::Leaf(key, _, left, right) => {
+                    *level = ProofNode::LeafHash(*key, value_hash, *left, *right);
+                    break;
+                }
+                _ => break,
+            }
+        }
+        Ok(proof)
+    }
+
+    /// Starts a `ProofContext` for proving many keys, fastest in key order.
+    pub fn proof_context(&self) -> ProofContext<'_> {
+        ProofContext {
+            tree: self,
+            path: Vec::new(),
+            reused: 0,
+        }
+    }
+
+    /// Replaces a stored value in `proof`'s leaf with the original, which
+    /// is what the leaf's value hash commits to.
+    fn decompress_proof(&self, mut proof: ProofNode) -> ProofNode {
+        let Some(codec) = &self.codec else {
+            return proof;
+        };
+        let mut level = &mut proof;
+        loop {
+            match level {
+                ProofNode::Left(_, _, child, _) | ProofNode::Right(_, _, _, child) => {
+                    level = &mut **child
+                }
+                ProofNode::Leaf(_, value, _, _) => {
+                    *value = (codec.decompress)(value);
+                    break;
+                }
+                _ => break,
+            }
+        }
+        proof
+    }
+
+    fn compute_proof(root: &Option<Box<Node>>, key: Key) -> Result<ProofNode, Error> {
+        #[cfg(test)]
+        PROOFS_GENERATED.with(|count| count.set(count.get() + 1));
+        Node::generate_proof(root, key)
+    }
+
+    /// Proves `key` as of `snapshot`, an earlier clone of this tree. Clones
+    /// share no nodes, so later changes to the live tree leave a snapshot's
+    /// hashes, and the proofs it generates, valid against its own root.
+    pub fn generate_proof_at(
+        &self,
+        snapshot: &MerkleAvlTree,
+        key: Key,
+    ) -> Result<ProofNode, Error> {
+        snapshot.generate_proof(key)
+    }
+
+    /// Generates a proof for `key` tagged with this tree's id.
+    pub fn generate_tagged_proof(&self, key: Key) -> Result<TaggedProof, Error> {
+        Ok(TaggedProof {
+            tree_id: self.tree_id,
+            proof: self.generate_proof(key)?,
+        })
+    }
+
+    /// Verifies a tagged proof against the root of the tree with id
+    /// `tree_id`. When both sides carry an id and they differ, returns
+    /// `Error::WrongTree` without looking at the hashes; otherwise behaves
+    /// like `verify_proof`.
+    pub fn verify_tagged_proof(
+        proof: &TaggedProof,
+        root_hash: RootHash,
+        tree_id: Option<TreeId>,
+    ) -> Result<(&Key, &Value), Error> {
+        if let (Some(proved), Some(expected)) = (proof.tree_id, tree_id) {
+            if proved != expected {
+                return Err(Error::WrongTree);
+            }
+        }
+        Self::verify_proof(&proof.proof, root_hash)
+    }
+
+    /// The length of `generate_proof(key)?.to_bytes()`, found by walking the
+    /// path without building the proof; 0 for an empty tree.
+    pub fn proof_size_estimate(&self, key: Key) -> usize {
+        if self.root.is_none() {
+            return 0;
+        }
+        const KEY: usize = std::mem::size_of::<Key>();
+        const HASH: usize = std::mem::size_of::<HashType>();
+        // Every level is a 4-byte length, then a tag byte.
+        const LEVEL: usize = 4 + 1;
+        let opt_hash = |link: &Option<Box<Node>>| 1 + link.as_ref().map_or(0, |_| HASH);
+        let mut size = 1;
+        let mut current = &self.root;
+        while let Some(n) = current {
+            let (next, sibling) = match key.cmp(&n.key) {
+                Ordering::Less => (&n.left, &n.right),
+                Ordering::Greater => (&n.right, &n.left),
+                // A tombstone ends the proof without a value.
+                Ordering::Equal if n.is_tombstone() => {
+                    return size + LEVEL + KEY + opt_hash(&n.left) + opt_hash(&n.right);
+                }
+                Ordering::Equal => {
+                    return size
+                        + LEVEL
+                        + KEY
+                        + opt_hash(&n.left)
+                        + opt_hash(&n.right)
+                        + 4
+                        + self.decode(&n.value).len();
+                }
+            };
+            size += LEVEL + KEY + HASH + opt_hash(sibling);
+            current = next;
+        }
+        size + LEVEL
+    }
+
+    pub fn verify_proof(proof: &ProofNode, root_hash: RootHash) -> Result<(&Key, &Value), Error> {
+        Self::verify_proof_with_salt(proof, root_hash, NO_SALT)
+    }
+
+    /// Checks that `proof` shows `key` soft-deleted under `root_hash`: it
+    /// hashes to the root, follows `key`'s search path and ends at a
+    /// tombstone for `key`. `salt` is the one the tree was built with
+    /// under `with_salt`, empty for an unsalted tree.
+    pub fn verify_tombstone(
+        key: Key,
+        proof: &ProofNode,
+        root_hash: RootHash,
+        salt: &[u8],
+    ) -> Result<(), Error> {
+        let (_, terminal) = proof.steps();
+        if proof.hash(salt) != root_hash.0 || !proof.follows(key) {
+            return Err(Error::InvalidProof);
+        }
+        match terminal {
+            ProofNode::Tombstone(..) => Ok(()),
+            _ => Err(Error::InvalidProof),
+        }
+    }
+
+    /// Checks a proof from `generate_proof_hidden` against `root_hash` and
+    /// returns the key it proves present. With `expected_value`, the value's
+    /// hash must also equal the one the proof commits to, as unsalted
+    /// `verify_proof` does; without it only the structure is checked.
+    pub fn verify_proof_hidden(
+        proof: &ProofNode,
+        root_hash: RootHash,
+        expected_value: Option<&Value>,
+    ) -> Result<Key, Error> {
+        if proof.hash(NO_SALT) != root_hash.0 {
+            return Err(Error::InvalidProof);
+        }
+        let (_, terminal) = proof.steps();
+        let ProofNode::LeafHash(key, value_hash, _, _) = terminal else {
+            return Err(Error::InvalidProof);
+        };
+        if !proof.follows(*key) {
+            return Err(Error::InvalidProof);
+        }
+        match expected_value {
+            Some(value) if Node::value_hash(NO_SALT, value) != *value_hash => {
+                Err(Error::InvalidProof)
+            }
+            _ => Ok(*key),
+        }
+    }
+
+    /// Verifies a proof from a tree built with `with_salt(salt)`.
+    pub fn verify_proof_with_salt<'a>(
+        proof: &'a ProofNode,
+        root_hash: RootHash,
+        salt: &[u8],
+    ) -> Result<(&'a Key, &'a Value), Error> {
+        if proof.hash(salt) == root_hash.0 {
+            proof.key_value().ok_or(match proof.steps().1 {
+                ProofNode::Tombstone(..) => Error::Deleted,
+                _ => Error::InvalidProof,
+            })
+        } else {
+            Err(Error::InvalidProof)
+        }
+    }
+
+    /// Like `verify_proof`, but first rejects with `Error::ProofTooDeep` a
+    /// proof passing through more than `max_depth` nodes, such as one padded
+    /// past a tree's known height, or more than `MAX_HEIGHT`, which no tree
+    /// reaches. Counting stops at the bound, so an oversized proof costs no
+    /// more than `max_depth` steps.
+    pub fn verify_proof_bounded(
+        proof: &ProofNode,
+        root_hash: RootHash,
+        max_depth: usize,
+    ) -> Result<(&Key, &Value), Error> {
+        let max_depth = max_depth.min(Self::MAX_HEIGHT as usize);
+        let mut depth = 0;
+        let mut current = Some(proof);
+        while let Some(level) = current {
+            if !matches!(level, ProofNode::Empty) {
+                depth += 1;
+            }
+            if depth > max_depth {
+                return Err(Error::ProofTooDeep);
+            }
+            current = match level {
+                ProofNode::Left(_, _, child_proof, _) | ProofNode::Right(_, _, _, child_proof) => {
+                    Some(child_proof)
+                }
+                _ => None,
+            };
+        }
+        Self::verify_proof(proof, root_hash)
+    }
+
+    /// Verifies `proof` and returns the proved value only if the proof is
+    /// for `queried_key`, rather than trusting whatever key the leaf claims.
+    pub fn verify_membership(
+        proof: &ProofNode,
+        root_hash: RootHash,
+        queried_key: Key,
+    ) -> Result<Value, Error> {
+        Self::verify_membership_with_salt(proof, root_hash, queried_key, NO_SALT)
+    }
+
+    /// `verify_membership` for a proof from a tree built with
+    /// `with_salt(salt)`.
+    pub fn verify_membership_with_salt(
+        proof: &ProofNode,
+        root_hash: RootHash,
+        queried_key: Key,
+        salt: &[u8],
+    ) -> Result<Value, Error> {
+        let (key, value) = Self::verify_proof_with_salt(proof, root_hash, salt)?;
+        if *key != queried_key || !proof.follows(queried_key) {
+            return Err(Error::KeyMismatch);
+        }
+        Ok(value.clone())
+    }
+
+    /// Caches the subtree hashes along `proof`'s path. The cache is only as
+    /// trustworthy as the proof, so check its `root` against a trusted one
+    /// before relying on it; `verify_membership_with_cache` does.
+    pub fn cache_from_proof(proof: &ProofNode) -> ProofCache {
+        let mut levels = Vec::new();
+        let mut current = proof;
+        loop {
+            let (key, left, right, went_left, next) = match current {
+                ProofNode::Left(key, _, child_proof, right) => {
+                    (key, child_proof.child_hash(NO_SALT), *right, Some(true), Some(child_proof))
+                }
+                ProofNode::Right(key, _, left, child_proof) => {
+                    (key, *left, child_proof.child_hash(NO_SALT), Some(false), Some(child_proof))
+                }
+                ProofNode::EmptyLeft(key, _, right) => (key, None, *right, Some(true), None),
+                ProofNode::EmptyRight(key, _, left) => (key, *left, None, Some(false), None),
+                ProofNode::Leaf(key, _, left, right)
+                | ProofNode::LeafHash(key, _, left, right)
+                | ProofNode::Tombstone(key, left, right) => (key, *left, *right, None, None),
+                ProofNode::Empty => break,
+            };
+            levels.push(CachedLevel {
+                key: *key,
+                hash: current.hash(NO_SALT),
+                left,
+                right,
+                went_left,
+            });
+            match next {
+                Some(next) => current = next,
+                None => break,
+            }
+        }
+        ProofCache { levels }
+    }
+
+    /// Verifies membership like `verify_membership` given only the part of
+    /// the proof below the deepest subtree `cache` holds on `queried_key`'s
+    /// path, as `ProofCache::shrink` cuts it. `cache` must itself be for
+    /// `root_hash`, or the result is `Error::InvalidProof`.
+    pub fn verify_membership_with_cache(
+        proof: &ProofNode,
+        cache: &ProofCache,
+        root_hash: RootHash,
+        queried_key: Key,
+    ) -> Result<Value, Error> {
+        if cache.root() != Some(root_hash) {
+            return Err(Error::InvalidProof);
+        }
+        match cache.anchor(queried_key) {
+            (_, Some(subtree_hash)) => {
+                Self::verify_membership(proof, RootHash(subtree_hash), queried_key)
+            }
+            (_, None) => Err(Error::InvalidProof),
+        }
+    }
+
+    /// Like `verify_proof`, but first rejects proofs whose shape couldn't
+    /// have come from a tree with `Error::MalformedProof`: every key on the
+    /// path must lie inside the range left by the turns above it, and the
+    /// path must end at the proved leaf or, for an absence proof, at an
+    /// `Empty`, `EmptyLeft` or `EmptyRight` position. A well-formed absence
+    /// proof that hashes to `root_hash` gives `Error::NotFound`.
+    pub fn verify_proof_strict(
+        proof: &ProofNode,
+        root_hash: RootHash,
+    ) -> Result<(&Key, &Value), Error> {
+        let (steps, terminal) = proof.steps();
+        let leaf = match terminal {
+            ProofNode::Leaf(key, _, _, _) => Some((*key, false)),
+            ProofNode::Empty => None,
+            _ => return Err(Error::MalformedProof),
+        };
+        let mut lower = None;
+        let mut upper = None;
+        for (key, went_left) in steps.into_iter().chain(leaf) {
+            if lower.is_some_and(|lower| key <= lower) || upper.is_some_and(|upper| key >= upper) {
+                return Err(Error::MalformedProof);
+            }
+            if went_left {
+                upper = Some(key);
+            } else {
+                lower = Some(key);
+            }
+        }
+        if leaf.is_none() && proof.hash(NO_SALT) == root_hash.0 {
+            return Err(Error::NotFound);
+        }
+        Self::verify_proof(proof, root_hash)
+    }
+
+    /// Proves that the entries returned by `verify_completeness_proof` are
+    /// every entry with a key in `[lo, hi]`. Panics if `lo > hi`.
+    pub fn generate_completeness_proof(
+        &self,
+        lo: Key,
+        hi: Key,
+    ) -> Result<CompletenessProof, Error> {
+        assert!(lo <= hi, "range bounds are reversed");
+        let start = self.generate_proof(lo)?;
+        let mut keys = Vec::new();
+        Node::visit_range(&self.root, lo, hi, &mut |n| {
+            if n.key != lo {
+                keys.push(n.key)
+            }
+        });
+        let entries = keys
+            .into_iter()
+            .map(|key| self.generate_proof(key))
+            .collect::<Result<_, _>>()?;
+        let end = Node::successor(&self.root, hi)
+            .map(|n| self.generate_proof(n.key))
+            .transpose()?;
+        Ok(CompletenessProof { lo, hi, start, entries, end })
+    }
+
+    /// Checks a completeness proof against `root_hash`, returning the
+    /// in-range entries in key order and leaving out any `soft_delete`
+    /// tombstones among them. Rejects with `Error::InvalidProof` if any
+    /// proof fails or an in-range entry is missing.
+    pub fn verify_completeness_proof(
+        proof: &CompletenessProof,
+        root_hash: RootHash,
+    ) -> Result<Vec<(&Key, &Value)>, Error> {
+        if proof.lo > proof.hi
+            || proof.start.hash(NO_SALT) != root_hash.0
+            || !proof.start.follows(proof.lo)
+        {
+            return Err(Error::InvalidProof);
+        }
+        let mut found: Vec<_> = proof.start.key_value().into_iter().collect();
+        let mut previous = &proof.start;
+        for entry in &proof.entries {
+            ProofNode::check_neighbor(previous, Some(entry), root_hash, false)?;
+            match (entry.key_value(), entry.terminal_node()) {
+                (Some((key, value)), _) if *key <= proof.hi => found.push((key, value)),
+                // In-range tombstones are stepped over.
+                (None, Some((key, _, _))) if key <= proof.hi => {}
+                _ => return Err(Error::InvalidProof),
+            }
+            previous = entry;
+        }
+        ProofNode::check_neighbor(previous, proof.end.as_ref(), root_hash, false)?;
+        match proof.end.as_ref().and_then(|end| end.terminal_node()) {
+            Some((key, _, _)) if key <= proof.hi => Err(Error::InvalidProof),
+            _ => Ok(found),
+        }
+    }
+
+    pub fn neighbor_proof(&self, key: Key) -> Result<NeighborProof, Error> {
+        let prove = |node: Option<&Node>| {
+            node.map(|n| self.generate_proof(n.key)).transpose()
+        };
+        Ok(NeighborProof {
+            key,
+            target: self.generate_proof(key)?,
+            predecessor: prove(Node::predecessor(&self.root, key))?,
+            successor: prove(Node::successor(&self.root, key))?,
+        })
+    }
+
+    /// Checks that every proof in the bundle verifies against `root_hash` and
+    /// that the neighbors really are adjacent to `proof.key`, so no entry
+    /// can exist between them. A neighbor may be a `soft_delete` tombstone.
+    pub fn verify_neighbor_proof(proof: &NeighborProof, root_hash: RootHash) -> Result<(), Error> {
+        if proof.target.hash(NO_SALT) != root_hash.0 || !proof.target.follows(proof.key) {
+            return Err(Error::InvalidProof);
+        }
+        ProofNode::check_neighbor(&proof.target, proof.predecessor.as_ref(), root_hash, true)?;
+        ProofNode::check_neighbor(&proof.target, proof.successor.as_ref(), root_hash, false)
+    }
+
+    /// Cross-checks the proof machinery against a direct lookup: a present
+    /// key's proof must verify against the current root and carry the same
+    /// value, a soft-deleted key's must be a valid tombstone proof and an
+    /// absent key's a valid absence proof.
+    pub fn self_check_proof(&self, key: Key) -> Result<(), Error> {
+        let proof = self.generate_proof(key)?;
+        let root_hash = self.root_hash().ok_or(Error::Empty)?;
+        match Node::lookup(&self.root, key) {
+            Err(Error::Deleted) => Self::verify_tombstone(key, &proof, root_hash, &self.salt),
+            Ok(value) => {
+                let proved = Self::verify_proof_with_salt(&proof, root_hash, &self.salt)?;
+                if proved == (&key, value) {
+                    Ok(())
+                } else {
+                    Err(Error::InvalidProof)
+                }
+            }
+            Err(_) => {
+                let (_, terminal) = proof.steps();
+                if proof.hash(&self.salt) == root_hash.0
+                    && *terminal == ProofNode::Empty
+                    && proof.follows(key)
+                {
+                    Ok(())
+                } else {
+                    Err(Error::InvalidProof)
+                }
+            }
+        }
+    }
+
+    /// Checks `proof` against `root_hash` like `verify_proof`, and on a
+    /// mismatch returns the hash the proof yields at every level. A bare root
+    /// only shows that the top level is wrong; `VerifyTrace::divergence`
+    /// compares the levels with the tree the proof claims to come from to
+    /// find the one that went wrong.
+    pub fn debug_verify(proof: &ProofNode, root_hash: RootHash) -> Result<(), VerifyTrace> {
+        if proof.hash(NO_SALT) == root_hash.0 {
+            return Ok(());
+        }
+        let mut levels = Vec::new();
+        let mut current = Some(proof);
+        while let Some(proof) = current {
+            let (next, went_left) = match proof {
+                ProofNode::Left(_, _, child_proof, _) => (Some(&**child_proof), Some(true)),
+                ProofNode::Right(_, _, _, child_proof) => (Some(&**child_proof), Some(false)),
+                ProofNode::EmptyLeft(..) => (Some(&EMPTY_PROOF), Some(true)),
+                ProofNode::EmptyRight(..) => (Some(&EMPTY_PROOF), Some(false)),
+                _ => (None, None),
+            };
+            levels.push(TraceLevel {
+                key: proof.key(),
+                computed: proof.hash(NO_SALT),
+                went_left,
+            });
+            current = next;
+        }
+        Err(VerifyTrace { root_hash, levels })
+    }
+
+    /// Checks a deletion transition: `(key, value)` was proved present under
+    /// `old_root`, and `absence_proof` shows `key` is missing under
+    /// `new_root`.
+    pub fn verify_deletion(
+        key: Key,
+        value: &Value,
+        old_root: RootHash,
+        membership_proof: &ProofNode,
+        new_root: RootHash,
+        absence_proof: &ProofNode,
+    ) -> Result<(), Error> {
+        if Self::verify_proof(membership_proof, old_root)? != (&key, value) {
+            return Err(Error::InvalidProof);
+        }
+        let (_, terminal) = absence_proof.steps();
+        if absence_proof.hash(NO_SALT) != new_root.0
+            || *terminal != ProofNode::Empty
+            || !absence_proof.follows(key)
+        {
+            return Err(Error::InvalidProof);
+        }
+        Ok(())
+    }
+
+    /// Proves that the root this tree had when it held `old_len` entries is
+    /// consistent with the current one.
+    ///
+    /// Assumes the tree is append-only: every insert used a key greater than
+    /// all keys before it, and nothing was deleted or overwritten. Its shape
+    /// then depends only on the keys in order, so a verifier can rebuild
+    /// both roots by replaying inserts. Rotations rewrite interior hashes as
+    /// the tree grows, so unlike a Certificate Transparency log the proof
+    /// is linear in the tree size. Returns `Error::NotFound` if `old_len`
+    /// exceeds `len`, and `Error::InvalidProof` if the tree doesn't replay
+    /// to its own root, meaning it wasn't built append-only.
+    pub fn generate_consistency_proof(&self, old_len: usize) -> Result<ConsistencyProof, Error> {
+        if old_len > self.len {
+            return Err(Error::NotFound);
+        }
+        let mut entries = Vec::with_capacity(self.len);
+        Node::visit_range(&self.root, Key::MIN, Key::MAX, &mut |n| {
+            entries.push((n.key, n.value_hash))
+        });
+        let appended = entries.split_off(old_len);
+        let proof = ConsistencyProof { old: entries, appended };
+        if Self::replay(&proof.old, proof.appended.iter()).1 != self.root_hash() {
+            return Err(Error::InvalidProof);
+        }
+        Ok(proof)
+    }
+
+    /// Checks that `proof` replays to `old_root` after its old entries and
+    /// to `new_root` after the appended ones, with keys strictly ascending
+    /// throughout, so no old entry was removed or overwritten.
+    pub fn verify_consistency_proof(
+        old_root: Option<RootHash>,
+        new_root: Option<RootHash>,
+        proof: &ConsistencyProof,
+    ) -> bool {
+        let keys = proof.old.iter().chain(&proof.appended).map(|(key, _)| key);
+        if keys.clone().zip(keys.skip(1)).any(|(a, b)| a >= b) {
+            return false;
+        }
+        (old_root, new_root) == Self::replay(&proof.old, proof.appended.iter())
+    }
+
+    /// The roots after inserting `old`, then after also inserting `appended`.
+    fn replay<'a>(
+        old: &[(Key, HashType)],
+        appended: impl Iterator<Item = &'a (Key, HashType)>,
+    ) -> (Option<RootHash>, Option<RootHash>) {
+        // Nodes commit to value hashes only, so the values can stay empty.
+        let mut tree = MerkleAvlTree::new();
+        for &(key, value_hash) in old {
+            tree.insert_hashed(key, Value::new(), value_hash);
+        }
+        let old_root = tree.root_hash();
+        for &(key, value_hash) in appended {
+            tree.insert_hashed(key, Value::new(), value_hash);
+        }
+        (old_root, tree.root_hash())
+    }
+
+    /// The peaks of a Merkle mountain range over this tree's entries, largest
+    /// first. The leaves, in ascending key order, are the hashes each entry
+    /// would commit to as a childless node, salt included; the MMR is built
+    /// by appending them in that order, so peak `i` covers the next
+    /// `2^h` leaves for the `i`-th highest set bit `h` of `len`.
+    pub fn to_mmr_peaks(&self) -> Vec<HashType> {
+        let mut stack: Vec<(HashType, u32)> = Vec::new();
+        for leaf in self.mmr_leaves() {
+            let mut top = (leaf, 0);
+            while stack.last().is_some_and(|&(_, height)| height == top.1) {
+                let (left, height) = stack.pop().unwrap();
+                top = (Node::mmr_parent(left, top.0), height + 1);
+            }
+            stack.push(top);
+        }
+        stack.into_iter().map(|(hash, _)| hash).collect()
+    }
+
+    /// Proves `key`'s leaf is under one of the `to_mmr_peaks` peaks.
+    pub fn generate_mmr_proof(&self, key: Key) -> Result<MmrProof, Error> {
+        let (mut index, mut rank) = (None, 0);
+        Node::visit_range(&self.root, Key::MIN, Key::MAX, &mut |n| {
+            if n.key == key {
+                index = Some(rank);
+            }
+            rank += 1;
+        });
+        let index = index.ok_or(Error::NotFound)?;
+        let leaves = self.mmr_leaves();
+        let mut start = 0;
+        let heights = (0..usize::BITS).rev().filter(|h| self.len >> h & 1 == 1);
+        for (peak, height) in heights.enumerate() {
+            let size = 1 << height;
+            if index >= start + size {
+                start += size;
+                continue;
+            }
+            let mut position = index - start;
+            let mut level = leaves[start..start + size].to_vec();
+            let mut siblings = Vec::with_capacity(height as usize);
+            while level.len() > 1 {
+                siblings.push(level[position ^ 1]);
+                level = level.chunks(2).map(|pair| Node::mmr_parent(pair[0], pair[1])).collect();
+                position /= 2;
+            }
+            return Ok(MmrProof { peak, position: index - start, siblings });
+        }
+        unreachable!("index {} is below len {}", index, self.len)
+    }
+
+    /// Checks that `proof` hashes `leaf_hash` up to `peaks[proof.peak]`.
+    pub fn verify_against_mmr(leaf_hash: HashType, proof: &MmrProof, peaks: &[HashType]) -> bool {
+        if proof.siblings.len() >= usize::BITS as usize
+            || proof.position >> proof.siblings.len() != 0
+        {
+            return false;
+        }
+        let mut hash = leaf_hash;
+        for (level, &sibling) in proof.siblings.iter().enumerate() {
+            hash = if proof.position >> level & 1 == 0 {
+                Node::mmr_parent(hash, sibling)
+            } else {
+                Node::mmr_parent(sibling, hash)
+            };
+        }
+        peaks.get(proof.peak) == Some(&hash)
+    }
+
+    /// The MMR leaves: each entry's childless node hash, in key order.
+    fn mmr_leaves(&self) -> Vec<HashType> {
+        let mut leaves = Vec::with_capacity(self.len);
+        Node::visit_range(&self.root, Key::MIN, Key::MAX, &mut |n| {
+            leaves.push(Node::compute_hash(&n.key, n.value_hash, None, None))
+        });
+        leaves
+    }
+
+    /// Encodes `proof` as a list of nodes, root first, one per level, for
+    /// verifiers that consume Merkle-Patricia style node lists.
+    ///
+    /// Each node is a tag byte followed by big-endian fields. Keys are 4
+    /// bytes, hashes `HASH_BYTES`, an optional hash is a presence byte
+    /// (0 or 1) then the hash if present, and a value is a 4-byte length
+    /// then UTF-8 bytes:
+    ///
+    /// | tag | node         | fields                          |
+    /// |-----|--------------|---------------------------------|
+    /// | 0   | `Left`       | key, value hash, right hash?    |
+    /// | 1   | `Right`      | key, value hash, left hash?     |
+    /// | 2   | `Leaf`       | key, left hash?, right hash?, value |
+    /// | 3   | `Empty`      |                                 |
+    /// | 4   | `EmptyLeft`  | key, value hash, right hash?    |
+    /// | 5   | `EmptyRight` | key, value hash, left hash?     |
+    /// | 6   | `Tombstone`  | key, left hash?, right hash?    |
+    /// | 7   | `LeafHash`   | key, value hash, left hash?, right hash? |
+    ///
+    /// `Left` and `Right` are followed by the node for their child; the last
+    /// node is always one of the others.
+    pub fn proof_to_mpt_nodes(proof: &ProofNode) -> Vec<Vec<u8>> {
+        let mut nodes = Vec::new();
+        let mut current = Some(proof);
+        while let Some(proof) = current {
+            let mut node = Vec::new();
+            current = proof.encode_level(&mut node);
+            nodes.push(node);
+        }
+        nodes
+    }
+
+    /// Decodes the output of `proof_to_mpt_nodes`, rejecting more levels
+    /// than any tree's proof has with `Error::ProofTooDeep` and anything
+    /// else with `Error::MalformedProof`.
+    pub fn proof_from_mpt_nodes(nodes: &[Vec<u8>]) -> Result<ProofNode, Error> {
+        // Up to `MAX_HEIGHT` nodes, then possibly the empty position below.
+        if nodes.len() > Self::MAX_HEIGHT as usize + 1 {
+            return Err(Error::ProofTooDeep);
+        }
+        let (last, parents) = nodes.split_last().ok_or(Error::MalformedProof)?;
+        let mut proof = ProofNode::decode_level(&mut ByteReader::new(last), None)?;
+        for node in parents.iter().rev() {
+            proof = ProofNode::decode_level(&mut ByteReader::new(node), Some(proof))?;
+        }
+        Ok(proof)
+    }
+
+    /// Whether applying `ops` in order to a clone of `initial` succeeds and
+    /// ends at `expected_root`, with `EMPTY_HASH` standing for an empty
+    /// tree. An insert or delete that fails makes the transition invalid;
+    /// lookups are applied too, since under `with_lru_capacity` they decide
+    /// later evictions, but a missing key is no failure. The clone drops
+    /// `initial`'s probe and proof cache, so neither sees the replay.
+    pub fn verify_transition(
+        initial: &MerkleAvlTree,
+        ops: &[Operation],
+        expected_root: RootHash,
+    ) -> bool {
+        let mut tree = MerkleAvlTree {
+            probe: Probe::default(),
+            proof_cache: None,
+            ..initial.clone()
+        };
+        let applied = ops.iter().all(|op| match op {
+            Operation::Insert(key, value) => tree.insert(*key, value.clone()).is_ok(),
+            Operation::Delete(key) => tree.delete(*key).is_ok(),
+            Operation::Lookup(key) => {
+                let _ = tree.lookup(*key);
+                true
+            }
+        });
+        applied && tree.root_or_empty() == expected_root.0
+    }
+
+    /// The root this tree would have after applying `op`, without changing
+    /// it. Only nodes on the operation's path, and the few a rotation moves,
+    /// are rehashed; every other subtree's stored hash is reused. Fails with
+    /// the error applying `op` would return. Evictions under
+    /// `with_lru_capacity` and rebuilds under `with_rebuild_threshold` are
+    /// not modeled.
+    pub fn next_root(&self, op: &Operation) -> Result<Option<RootHash>, Error> {
+        let root = Shadow::of(&self.root);
+        let next = match op {
+            Operation::Insert(key, value) => {
+                if self.max_value_len.is_some_and(|max_len| value.len() > max_len) {
+                    return Err(Error::ValueTooLarge);
+                }
+                if !self.accepts_key(*key) {
+                    return Err(Error::KeyOutOfRange);
+                }
+                root.insert(*key, self.hash_value(value))
+            }
+            Operation::Delete(key) => {
+                if self.root.is_none() {
+                    return Err(Error::Empty);
+                }
+                root.delete(*key)?
+            }
+            Operation::Lookup(_) => root,
+        };
+        Ok(next.hash().map(RootHash))
+    }
+
+    /// Whether the entry a membership proof proved, against the root before
+    /// `ops_since`, still holds under `new_root` after those operations,
+    /// judged from the operations alone. The last insert or delete of the
+    /// proved key decides; if none touches it the entry is unchanged.
+    /// Returns `None` when that can't be told: `proof` isn't a membership
+    /// proof, or no operations are given yet `proof` doesn't match
+    /// `new_root`, so the list must be missing some.
+    pub fn proof_still_valid(
+        proof: &ProofNode,
+        ops_since: &[Operation],
+        new_root: RootHash,
+    ) -> Option<bool> {
+        let (key, value) = proof.key_value()?;
+        if ops_since.is_empty() {
+            return (proof.hash(NO_SALT) == new_root.0).then_some(true);
+        }
+        let last_change = ops_since.iter().rev().find_map(|op| match op {
+            Operation::Insert(k, v) if k == key => Some(Some(v)),
+            Operation::Delete(k) if k == key => Some(None),
+            _ => None,
+        });
+        Some(match last_change {
+            Some(new_value) => new_value == Some(value),
+            None => true,
+        })
+    }
+
+    /// Predicts the root after the proved key's value is replaced by
+    /// `new_value`, using only the proof path. The proof must verify against
+    /// `old_root`.
+    pub fn updated_root(
+        proof: &ProofNode,
+        new_value: &Value,
+        old_root: RootHash,
+    ) -> Result<RootHash, Error> {
+        Self::verify_proof(proof, old_root)?;
+        Ok(RootHash(proof.with_value(new_value).hash(NO_SALT)))
+    }
+}
+
+impl VerifyTrace {
+    /// The deepest level whose hash disagrees with `tree`'s node at the same
+    /// position, or with `EMPTY_HASH` where the proof runs past the bottom.
+    /// Every level above it only differs because this one does, so this is
+    /// where the bad data is.
+    pub fn divergence(&self, tree: &MerkleAvlTree) -> Option<usize> {
+        let mut node = Some(&tree.root);
+        let mut deepest = None;
+        for (depth, level) in self.levels.iter().enumerate() {
+            let expected = node.and_then(Node::hash_of).unwrap_or(EMPTY_HASH);
+            if level.computed != expected {
+                deepest = Some(depth);
+            }
+            node = node
+                .and_then(|n| n.as_ref())
+                .zip(level.went_left)
+                .map(|(n, went_left)| if went_left { &n.left } else { &n.right });
+        }
+        deepest
+    }
+}
+
+impl LruOrder {
+    fn new(capacity: usize) -> LruOrder {
+        LruOrder {
+            capacity,
+            tick: 0,
+            ticks: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn touch(&mut self, key: Key) {
+        self.remove(key);
+        self.tick += 1;
+        self.ticks.insert(key, self.tick);
+        self.order.insert(self.tick, key);
+    }
+
+    fn remove(&mut self, key: Key) {
+        if let Some(tick) = self.ticks.remove(&key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    /// Marks `key` as most recently used, returning the key to evict if it
+    /// is new and the tree is already full.
+    fn admit(&mut self, key: Key) -> Option<Key> {
+        let evicted = if !self.ticks.contains_key(&key) && self.ticks.len() >= self.capacity {
+            let (_, oldest) = self.order.pop_first()?;
+            self.ticks.remove(&oldest);
+            Some(oldest)
+        } else {
+            None
+        };
+        self.touch(key);
+        evicted
+    }
+}
+
+impl ValueIndex {
+    fn add(&mut self, key: Key, value: &Value) {
+        let keys = self.keys.entry((self.projection)(value)).or_default();
+        if let Err(at) = keys.binary_search(&key) {
+            keys.insert(at, key);
+        }
+    }
+
+    fn remove(&mut self, key: Key, value: &Value) {
+        let value_key = (self.projection)(value);
+        if let Some(keys) = self.keys.get_mut(&value_key) {
+            if let Ok(at) = keys.binary_search(&key) {
+                keys.remove(at);
+            }
+            if keys.is_empty() {
+                self.keys.remove(&value_key);
+            }
+        }
+    }
+}
+
+impl CachedProofs {
+    fn new(capacity: usize) -> CachedProofs {
+        CachedProofs {
+            root: None,
+            order: LruOrder::new(capacity),
+            proofs: HashMap::new(),
+            stats: ProofCacheStats::default(),
+        }
+    }
+
+    /// The cached proof for `key` under `root`, first dropping everything
+    /// cached under a different root.
+    fn get(&mut self, key: Key, root: Option<HashType>) -> Option<ProofNode> {
+        if self.root != root {
+            self.root = root;
+            self.order = LruOrder::new(self.order.capacity);
+            self.proofs.clear();
+        }
+        match self.proofs.get(&key) {
+            Some(proof) => {
+                self.stats.hits += 1;
+                self.order.touch(key);
+                Some(proof.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn put(&mut self, key: Key, proof: ProofNode) {
+        if let Some(evicted) = self.order.admit(key) {
+            self.proofs.remove(&evicted);
+        }
+        self.proofs.insert(key, proof);
+    }
+}
+
+impl StreamingCommitter {
+    /// Expects exactly `len` entries.
+    pub fn new(len: usize) -> StreamingCommitter {
+        StreamingCommitter {
+            stack: (len > 0).then_some(CommitFrame::new(len)).into_iter().collect(),
+            root: None,
+            previous: None,
+        }
+    }
+
+    /// Adds the next entry; keys must be strictly ascending. Panics after
+    /// `len` entries.
+    pub fn push(&mut self, key: Key, value: &Value) {
+        debug_assert!(self.previous.is_none_or(|previous| previous < key), "keys not ascending");
+        self.previous = Some(key);
+        // Descend to the leftmost subtree still waiting for its left side.
+        loop {
+            let frame = self.stack.last_mut().expect("more entries than announced");
+            if frame.left.is_some() {
+                break;
+            }
+            match frame.len / 2 {
+                0 => frame.left = Some(None),
+                left_len => self.stack.push(CommitFrame::new(left_len)),
+            }
+        }
+        let frame = self.stack.last_mut().expect("frame found above");
+        frame.node = Some((key, Node::value_hash(NO_SALT, value)));
+        match frame.len - frame.len / 2 - 1 {
+            0 => self.finish(None),
+            right_len => self.stack.push(CommitFrame::new(right_len)),
+        }
+    }
+
+    /// The root over everything pushed, `None` for no entries. Panics if
+    /// fewer than `len` entries were pushed.
+    pub fn finalize(self) -> Option<RootHash> {
+        assert!(self.stack.is_empty(), "fewer entries than announced");
+        self.root.map(RootHash)
+    }
+
+    /// Completes the top frame, whose right subtree hashes to `right`, and
+    /// every ancestor this completes in turn.
+    fn finish(&mut self, mut right: Option<HashType>) {
+        while let Some(frame) = self.stack.pop() {
+            let (key, value_hash) = frame.node.expect("node pushed before finishing");
+            let left = frame.left.expect("left finished before node");
+            let hash = Node::compute_hash(&key, value_hash, left, right);
+            match self.stack.last_mut() {
+                Some(parent) if parent.node.is_none() => {
+                    parent.left = Some(Some(hash));
+                    return;
+                }
+                Some(_) => right = Some(hash),
+                None => self.root = Some(hash),
+            }
+        }
+    }
+}
+
+impl CommitFrame {
+    fn new(len: usize) -> CommitFrame {
+        CommitFrame {
+            len,
+            left: None,
+            node: None,
+        }
+    }
+}
+
+impl<'a> ReadTx<'a> {
+    /// Looks up `key` as `MerkleAvlTree::lookup` does.
+    pub fn get(&self, key: Key) -> Option<Cow<'a, Value>> {
+        self.tree.lookup(key).ok()
+    }
+}
+
+impl<'a> ProofContext<'a> {
+    /// Proves `key` against the tree's current root; the proof is the one
+    /// `generate_proof` gives.
+    pub fn prove(&mut self, key: Key) -> Result<ProofNode, Error> {
+        let root = self.tree.root.as_deref().ok_or(Error::Empty)?;
+        while let Some(&(_, lower, upper)) = self.path.last() {
+            if lower.is_none_or(|lower| key > lower) && upper.is_none_or(|upper| key < upper) {
+                break;
+            }
+            self.path.pop();
+        }
+        self.reused += self.path.len();
+        if self.path.is_empty() {
+            self.path.push((root, None, None));
+        }
+        while let Some(&(n, lower, upper)) = self.path.last() {
+            let next = match key.cmp(&n.key) {
+                Ordering::Less => n.left.as_deref().map(|c| (c, lower, Some(n.key))),
+                Ordering::Greater => n.right.as_deref().map(|c| (c, Some(n.key), upper)),
+                Ordering::Equal => None,
+            };
+            match next {
+                Some(step) => self.path.push(step),
+                None => break,
+            }
+        }
+        let mut steps = self.path.iter().rev().map(|&(n, _, _)| n).peekable();
+        let mut proof = match steps.next_if(|n| n.key == key) {
+            Some(n) => Node::terminal_proof(n),
+            None => ProofNode::Empty,
+        };
+        for n in steps {
+            proof = if key < n.key {
+                ProofNode::Left(n.key, n.value_hash, Box::new(proof), Node::hash_of(&n.right))
+            } else {
+                ProofNode::Right(n.key, n.value_hash, Node::hash_of(&n.left), Box::new(proof))
+            };
+        }
+        Ok(self.tree.decompress_proof(proof))
+    }
+}
+
+impl ProofCache {
+    /// The root the cached proof hashes to, `None` for an empty tree's.
+    pub fn root(&self) -> Option<RootHash> {
+        self.levels.first().map(|level| RootHash(level.hash))
+    }
+
+    /// Cuts `proof`, a proof for `key`, down to the part below the deepest
+    /// cached subtree on `key`'s path, which is all a holder of this cache
+    /// needs sent.
+    pub fn shrink(&self, key: Key, proof: &ProofNode) -> ProofNode {
+        let (depth, _) = self.anchor(key);
+        let mut current = proof;
+        for _ in 0..depth {
+            current = match current {
+                ProofNode::Left(_, _, child_proof, _) | ProofNode::Right(_, _, _, child_proof) => {
+                    child_proof
+                }
+                ProofNode::EmptyLeft(..) | ProofNode::EmptyRight(..) => &EMPTY_PROOF,
+                _ => break,
+            };
+        }
+        current.clone()
+    }
+
+    /// The deepest cached subtree on `key`'s search path: how many levels
+    /// below the root it sits, and its hash, `None` for an empty subtree.
+    fn anchor(&self, key: Key) -> (usize, Option<HashType>) {
+        let Some(root) = self.levels.first() else {
+            return (0, None);
+        };
+        let mut anchor = (0, Some(root.hash));
+        for (depth, level) in self.levels.iter().enumerate() {
+            let went_left = match key.cmp(&level.key) {
+                Ordering::Equal => break,
+                Ordering::Less => true,
+                Ordering::Greater => false,
+            };
+            let child = if went_left { level.left } else { level.right };
+            anchor = (depth + 1, child);
+            if level.went_left != Some(went_left) || depth + 1 == self.levels.len() {
+                break;
+            }
+        }
+        anchor
+    }
+}
+
+impl FrozenTree {
+    pub fn lookup(&self, key: Key) -> Result<Cow<'_, Value>, Error> {
+        self.tree.lookup(key)
+    }
+
+    pub fn range(&self, lo: Key, hi: Key) -> impl Iterator<Item = (&Key, Cow<'_, Value>)> {
+        self.tree.range_iter(lo, hi)
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        self.tree.iter()
+    }
+
+    pub fn generate_proof(&self, key: Key) -> Result<ProofNode, Error> {
+        self.tree.generate_proof(key)
+    }
+
+    pub fn root_hash(&self) -> Option<RootHash> {
+        self.tree.root_hash()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    pub fn thaw(self) -> MerkleAvlTree {
+        self.tree
+    }
+}
+
+impl OutOfLineTree {
+    pub fn new() -> OutOfLineTree {
+        OutOfLineTree::default()
+    }
+
+    /// Records `value`'s hash under `key`; the caller keeps the bytes at
+    /// `handle`.
+    pub fn insert(&mut self, key: Key, value: &Value, handle: Handle) {
+        let value_hash = Node::value_hash(&self.tree.salt, value);
+        self.tree.insert_hashed(key, Value::new(), value_hash);
+        self.handles.insert(key, handle);
+    }
+
+    pub fn delete(&mut self, key: Key) -> Result<Handle, Error> {
+        self.tree.delete(key)?;
+        self.handles.remove(&key).ok_or(Error::NotFound)
+    }
+
+    /// Returns the committed value hash and the handle for `key`.
+    pub fn lookup(&self, key: Key) -> Result<(HashType, Handle), Error> {
+        let handle = *self.handles.get(&key).ok_or(Error::NotFound)?;
+        let value_hash = Node::find(&self.tree.root, key)
+            .map(|n| n.value_hash)
+            .ok_or(Error::NotFound)?;
+        Ok((value_hash, handle))
+    }
+
+    /// Resolves the bytes for `key` through `fetch`, rejecting them with
+    /// `Error::InvalidProof` if they don't match the committed hash.
+    pub fn fetch(&self, key: Key, fetch: impl FnOnce(Handle) -> Value) -> Result<Value, Error> {
+        let (value_hash, handle) = self.lookup(key)?;
+        let value = fetch(handle);
+        if Node::value_hash(&self.tree.salt, &value) == value_hash {
+            Ok(value)
+        } else {
+            Err(Error::InvalidProof)
+        }
+    }
+
+    pub fn root_hash(&self) -> Option<RootHash> {
+        self.tree.root_hash()
+    }
+}
+
+impl Node {
+    fn new(key: Key, value: Value, value_hash: HashType) -> Box<Node> {
+        Box::new(Node {
+            key,
+            value,
+            value_hash,
+            hash: Self::compute_hash(&key, value_hash, None, None),
+            height: 1,
+            left: None,
+            right: None,
+        })
+    }
+
+    /// The value hash a `soft_delete` tombstone commits to in place of its
+    /// value's, separated from value hashes by a domain tag.
+    fn tombstone_hash() -> HashType {
+        let mut hasher = WideHasher::new();
+        TOMBSTONE_DOMAIN.hash(&mut hasher);
+        hasher.finish_wide()
+    }
+
+    fn is_tombstone(&self) -> bool {
+        Self::is_tombstone_entry(&self.value, self.value_hash)
+    }
+
+    /// `is_tombstone` for an entry taken out of its node.
+    fn is_tombstone_entry(value: &Value, value_hash: HashType) -> bool {
+        value.is_empty() && value_hash == Self::tombstone_hash()
+    }
+
+    /// Turns the node holding `key`, which must exist, into a tombstone,
+    /// rehashing the path. Returns the value it held.
+    fn mark_tombstone(node: &mut Option<Box<Node>>, key: Key) -> Value {
+        let n = node.as_mut().expect("key is in the tree");
+        let value = match key.cmp(&n.key) {
+            Ordering::Less => Self::mark_tombstone(&mut n.left, key),
+            Ordering::Greater => Self::mark_tombstone(&mut n.right, key),
+            Ordering::Equal => {
+                n.value_hash = Self::tombstone_hash();
+                std::mem::take(&mut n.value)
+            }
+        };
+        Self::update_height_and_hash(n);
+        value
+    }
+
+    /// The last level of a proof for `n`'s own key.
+    fn terminal_proof(n: &Node) -> ProofNode {
+        if n.is_tombstone() {
+            ProofNode::Tombstone(n.key, Self::hash_of(&n.left), Self::hash_of(&n.right))
+        } else {
+            ProofNode::Leaf(n.key, n.value.clone(), Self::hash_of(&n.left), Self::hash_of(&n.right))
+        }
+    }
+
+    fn value_hash(salt: &[u8], value: &Value) -> HashType {
+        #[cfg(any(test, feature = "hash-stats"))]
+        VALUE_HASHES.with(|count| count.set(count.get() + 1));
+        let mut hasher = WideHasher::new();
+        if !salt.is_empty() {
+            salt.hash(&mut hasher);
+        }
+        value.hash(&mut hasher);
+        hasher.finish_wide()
+    }
+
+    /// A node commits to its key, the hash of its value and both child
+    /// positions, with `EMPTY_HASH` standing in for a missing child.
+    fn compute_hash(
+        key: &Key,
+        value_hash: HashType,
+        left: Option<HashType>,
+        right: Option<HashType>,
+    ) -> HashType {
+        #[cfg(any(test, feature = "hash-stats"))]
+        NODE_HASHES.with(|count| count.set(count.get() + 1));
+        let mut hasher = WideHasher::new();
+        key.hash(&mut hasher);
+        value_hash.hash(&mut hasher);
+        left.unwrap_or(EMPTY_HASH).hash(&mut hasher);
+        right.unwrap_or(EMPTY_HASH).hash(&mut hasher);
+        hasher.finish_wide()
+    }
+
+    fn skeleton(node: &Option<Box<Node>>) -> Option<Box<Skeleton>> {
+        node.as_ref().map(|n| {
+            Box::new(Skeleton {
+                key: n.key,
+                height: n.height,
+                left: Self::skeleton(&n.left),
+                right: Self::skeleton(&n.right),
+            })
+        })
+    }
+
+    /// Splits `keys`, sorted, between the subtrees along their search paths,
+    /// recording each path node in pre-order and each subtree no path enters.
+    fn frontier(
+        node: &Option<Box<Node>>,
+        keys: &[Key],
+        path_nodes: &mut Vec<(Key, HashType)>,
+        frontier: &mut Vec<(Key, HashType)>,
+    ) {
+        let Some(n) = node else {
+            return;
+        };
+        if keys.is_empty() {
+            frontier.push((n.key, n.hash));
+            return;
+        }
+        path_nodes.push((n.key, n.value_hash));
+        let below = keys.partition_point(|&key| key < n.key);
+        let above = keys.partition_point(|&key| key <= n.key);
+        Self::frontier(&n.left, &keys[..below], path_nodes, frontier);
+        Self::frontier(&n.right, &keys[above..], path_nodes, frontier);
+    }
+
+    /// An interior MMR node over two adjacent subtrees.
+    fn mmr_parent(left: HashType, right: HashType) -> HashType {
+        let mut hasher = WideHasher::new();
+        left.hash(&mut hasher);
+        right.hash(&mut hasher);
+        hasher.finish_wide()
+    }
+
+    /// Builds the canonical subtree over the next `len` entries of `iter`,
+    /// each carrying its value hash.
+    fn build_sorted(
+        iter: &mut impl Iterator<Item = (Key, Value, HashType)>,
+        previous: &mut Option<Key>,
+        len: usize,
+    ) -> Option<Box<Node>> {
+        if len == 0 {
+            return None;
+        }
+        let left_len = len / 2;
+        let left = Self::build_sorted(iter, previous, left_len);
+        let (key, value, value_hash) = iter
+            .next()
+            .expect("iterator yielded fewer entries than hint_len");
+        debug_assert!(
+            previous.is_none_or(|previous| previous < key),
+            "keys must be strictly ascending"
+        );
+        *previous = Some(key);
+        let right = Self::build_sorted(iter, previous, len - left_len - 1);
+
+        let mut node = Self::new(key, value, value_hash);
+        node.left = left;
+        node.right = right;
+        Self::update_height_and_hash(&mut node);
+        Some(node)
+    }
+
+    /// Moves every entry out of the subtree in key order.
+    fn drain_sorted(node: Option<Box<Node>>, out: &mut Vec<(Key, Value, HashType)>) {
+        if let Some(n) = node {
+            let n = *n;
+            Self::drain_sorted(n.left, out);
+            out.push((n.key, n.value, n.value_hash));
+            Self::drain_sorted(n.right, out);
+        }
+    }
+
+    fn hash_of(node: &Option<Box<Node>>) -> Option<HashType> {
+        node.as_ref().map(|n| n.hash)
+    }
+
+    fn height(node: &Option<Box<Node>>) -> i32 {
+        node.as_ref().map_or(0, |n| n.height)
+    }
+
+    /// Recombines the cached value hash with the children's hashes; the
+    /// value itself is only hashed when it changes.
+    fn update_height_and_hash(node: &mut Box<Node>) {
+        node.height = 1 + std::cmp::max(Self::height(&node.left), Self::height(&node.right));
+        node.hash = Self::compute_hash(
+            &node.key,
+            node.value_hash,
+            Self::hash_of(&node.left),
+            Self::hash_of(&node.right),
+        );
+    }
+
+    fn balance_factor(node: &Option<Box<Node>>) -> i32 {
+        Self::height(&node.as_ref().unwrap().right) - Self::height(&node.as_ref().unwrap().left)
+    }
+
+    fn rotate_left(mut node: Box<Node>) -> Box<Node> {
+        let mut new_root = node.right.take().unwrap();
+        node.right = new_root.left.take();
+        new_root.left = Some(node);
+
+        Self::update_height_and_hash(new_root.left.as_mut().unwrap());
+        Self::update_height_and_hash(&mut new_root);
+
+        new_root
+    }
+
+    fn rotate_right(mut node: Box<Node>) -> Box<Node> {
+        let mut new_root = node.left.take().unwrap();
+        node.left = new_root.right.take();
+        new_root.right = Some(node);
+
+        Self::update_height_and_hash(new_root.right.as_mut().unwrap());
+        Self::update_height_and_hash(&mut new_root);
+
+        new_root
+    }
+
+    /// Restores the AVL invariant at `node`, adding the number of single
+    /// rotations performed to `rotations`.
+    fn balance(node: Option<Box<Node>>, rotations: &mut usize) -> Option<Box<Node>> {
+        if let Some(mut n) = node {
+            let bf = Self::height(&n.right) - Self::height(&n.left);
+            if bf > 1 {
+                if Self::balance_factor(&n.right) < 0 {
+                    n.right = Some(Self::rotate_right(n.right.take().unwrap()));
+                    *rotations += 1;
+                }
+                *rotations += 1;
+                return Some(Self::rotate_left(n));
+            } else if bf < -1 {
+                if Self::balance_factor(&n.left) > 0 {
+                    n.left = Some(Self::rotate_left(n.left.take().unwrap()));
+                    *rotations += 1;
+                }
+                *rotations += 1;
+                return Some(Self::rotate_right(n));
+            }
+            Some(n)
+        } else {
+            None
+        }
+    }
+
+    fn insert(
+        node: Option<Box<Node>>,
+        key: Key,
+        value: Value,
+        value_hash: HashType,
+        rotations: &mut usize,
+    ) -> Option<Box<Node>> {
+        if let Some(mut n) = node {
+            match key.cmp(&n.key) {
+                Ordering::Less => {
+                    n.left = Self::insert(n.left.take(), key, value, value_hash, rotations);
+                }
+                Ordering::Greater => {
+                    n.right = Self::insert(n.right.take(), key, value, value_hash, rotations);
+                }
+                Ordering::Equal => {
+                    debug_assert!(key == n.key, "Ord and Eq disagree on a key");
+                    n.value = value;
+                    n.value_hash = value_hash;
+                }
+            }
+            Self::update_height_and_hash(&mut n);
+            Self::balance(Some(n), rotations)
+        } else {
+            Some(Self::new(key, value, value_hash))
+        }
+    }
+
+    fn delete(node: Option<Box<Node>>, key: Key) -> Result<(Link, Link), Error> {
+        if let Some(mut n) = node {
+            let deleted: Option<Box<Node>>;
+            match key.cmp(&n.key) {
+                Ordering::Less => {
+                    let (new_left, del) = Self::delete(n.left.take(), key)?;
+                    n.left = new_left;
+                    deleted = del;
+                }
+                Ordering::Greater => {
+                    let (new_right, del) = Self::delete(n.right.take(), key)?;
+                    n.right = new_right;
+                    deleted = del;
+                }
+                Ordering::Equal => {
+                    deleted = Some(n.clone());
+                    if n.left.is_none() {
+                        return Ok((n.right.take(), deleted));
+                    } else if n.right.is_none() {
+                        return Ok((n.left.take(), deleted));
+                    } else {
+                        let (new_right, min_right) = Self::delete_min(n.right.take().unwrap());
+                        n.key = min_right.key;
+                        n.value = min_right.value;
+                        n.value_hash = min_right.value_hash;
+                        n.right = new_right;
+                    }
+                }
+            }
+            Self::update_height_and_hash(&mut n);
+            Ok((Self::balance(Some(n), &mut 0), deleted))
+        } else {
+            Ok((None, None))
+        }
+    }
+
+    fn delete_min(mut node: Box<Node>) -> (Option<Box<Node>>, Box<Node>) {
+        if let Some(left) = node.left.take() {
+            let (new_left, min_node) = Self::delete_min(left);
+            node.left = new_left;
+            Self::update_height_and_hash(&mut node);
+            (Self::balance(Some(node), &mut 0), min_node)
+        } else {
+            (node.right.take(), node)
+        }
+    }
+
+    /// Calls `f` on every node with a key in `[lo, hi]`, in key order,
+    /// skipping subtrees that lie entirely outside the range.
+    fn visit_range<'a>(
+        node: &'a Option<Box<Node>>,
+        lo: Key,
+        hi: Key,
+        f: &mut impl FnMut(&'a Node),
+    ) {
+        if let Some(n) = node {
+            if n.key > lo {
+                Self::visit_range(&n.left, lo, hi, f);
+            }
+            if lo <= n.key && n.key <= hi {
+                f(n);
+            }
+            if n.key < hi {
+                Self::visit_range(&n.right, lo, hi, f);
+            }
+        }
+    }
+
+    fn hashes_ok(node: &Option<Box<Node>>, salt: &[u8], codec: Option<&ValueCodec>) -> bool {
+        match node {
+            Some(n) => {
+                Self::hashes_ok(&n.left, salt, codec)
+                    && Self::hashes_ok(&n.right, salt, codec)
+                    && Self::own_hashes_ok(n, salt, codec)
+            }
+            None => true,
+        }
+    }
+
+    /// Splits the check across threads for the top `depth` levels.
+    #[cfg(feature = "parallel")]
+    fn hashes_ok_parallel(node: &Option<Box<Node>>, salt: &[u8], depth: u32) -> bool {
+        let Some(n) = node else {
+            return true;
+        };
+        if depth == 0 {
+            return Self::hashes_ok(node, salt, None);
+        }
+        let (left, right) = std::thread::scope(|scope| {
+            let left = scope.spawn(|| Self::hashes_ok_parallel(&n.left, salt, depth - 1));
+            let right = Self::hashes_ok_parallel(&n.right, salt, depth - 1);
+            (left.join().expect("verifier thread panicked"), right)
+        });
+        left && right && Self::own_hashes_ok(n, salt, None)
+    }
+
+    fn own_hashes_ok(n: &Node, salt: &[u8], codec: Option<&ValueCodec>) -> bool {
+        (n.is_tombstone()
+            || n.value_hash == Self::value_hash(salt, &ValueCodec::decode(codec, &n.value)))
+            && n.hash
+                == Self::compute_hash(
+                    &n.key,
+                    n.value_hash,
+                    Self::hash_of(&n.left),
+                    Self::hash_of(&n.right),
+                )
+    }
+
+    fn find_violation(
+        node: &Option<Box<Node>>,
+        lower: Option<Key>,
+        upper: Option<Key>,
+    ) -> Option<Violation> {
+        let n = node.as_ref()?;
+        Self::find_violation(&n.left, lower, Some(n.key))
+            .or_else(|| Self::find_violation(&n.right, Some(n.key), upper))
+            .or_else(|| {
+                let violation = |kind| Some(Violation { key: n.key, kind });
+                let expected_height =
+                    1 + std::cmp::max(Self::height(&n.left), Self::height(&n.right));
+                let balance = Self::height(&n.right) - Self::height(&n.left);
+                let expected_hash = Self::compute_hash(
+                    &n.key,
+                    n.value_hash,
+                    Self::hash_of(&n.left),
+                    Self::hash_of(&n.right),
+                );
+                if lower.is_some_and(|lower| n.key <= lower)
+                    || upper.is_some_and(|upper| n.key >= upper)
+                {
+                    violation(ViolationKind::OutOfOrder { lower, upper })
+                } else if n.height != expected_height {
+                    violation(ViolationKind::BadHeight {
+                        expected: expected_height,
+                        actual: n.height,
+                    })
+                } else if balance.abs() > 1 {
+                    violation(ViolationKind::Unbalanced { balance })
+                } else if n.hash != expected_hash {
+                    violation(ViolationKind::BadHash {
+                        expected: expected_hash,
+                        actual: n.hash,
+                    })
+                } else {
+                    None
+                }
+            })
+    }
+
+    fn memory_usage(node: &Option<Box<Node>>) -> usize {
+        node.as_ref().map_or(0, |n| {
+            std::mem::size_of::<Node>()
+                + n.value.capacity()
+                + Self::memory_usage(&n.left)
+                + Self::memory_usage(&n.right)
+        })
+    }
+
+    /// Applies `f` to every value as inserted, then refreshes value hashes
+    /// and node hashes bottom-up, keeping `index` in step.
+    fn map_values(
+        node: &mut Option<Box<Node>>,
+        salt: &[u8],
+        codec: Option<&ValueCodec>,
+        f: &mut impl FnMut(&Key, &mut Value),
+        index: &mut Option<ValueIndex>,
+    ) {
+        if let Some(n) = node {
+            Self::map_values(&mut n.left, salt, codec, f, index);
+            Self::map_values(&mut n.right, salt, codec, f, index);
+            if n.is_tombstone() {
+                return;
+            }
+            let mut value = ValueCodec::decode(codec, &n.value).into_owned();
+            if let Some(index) = index {
+                index.remove(n.key, &value);
+            }
+            f(&n.key, &mut value);
+            if let Some(index) = index {
+                index.add(n.key, &value);
+            }
+            n.value_hash = Self::value_hash(salt, &value);
+            n.value = match codec {
+                Some(codec) => (codec.compress)(&value),
+                None => value,
+            };
+            Self::update_height_and_hash(n);
+        }
+    }
+
+    fn shrink_values(node: &mut Option<Box<Node>>) {
+        if let Some(n) = node {
+            n.value.shrink_to_fit();
+            Self::shrink_values(&mut n.left);
+            Self::shrink_values(&mut n.right);
+        }
+    }
+
+    /// The node with the largest key below `key`.
+    fn predecessor(node: &Option<Box<Node>>, key: Key) -> Option<&Node> {
+        let mut current = node;
+        let mut best = None;
+        while let Some(n) = current {
+            if n.key < key {
+                best = Some(&**n);
+                current = &n.right;
+            } else {
+                current = &n.left;
+            }
+        }
+        best
+    }
+
+    /// The node with the smallest key above `key`.
+    fn successor(node: &Option<Box<Node>>, key: Key) -> Option<&Node> {
+        let mut current = node;
+        let mut best = None;
+        while let Some(n) = current {
+            if n.key > key {
+                best = Some(&**n);
+                current = &n.left;
+            } else {
+                current = &n.right;
+            }
+        }
+        best
+    }
+
+    fn collect_hashes(node: &Option<Box<Node>>, hashes: &mut HashSet<HashType>) {
+        if let Some(n) = node {
+            hashes.insert(n.hash);
+            Self::collect_hashes(&n.left, hashes);
+            Self::collect_hashes(&n.right, hashes);
+        }
+    }
+
+    /// Appends, in key order, the keys under `node` that `other` lacks,
+    /// skipping subtrees whose hash is in `shared`.
+    fn keys_missing_from(
+        node: &Option<Box<Node>>,
+        shared: &HashSet<HashType>,
+        other: &Option<Box<Node>>,
+        missing: &mut Vec<Key>,
+    ) {
+        if let Some(n) = node {
+            if shared.contains(&n.hash) {
+                return;
+            }
+            Self::keys_missing_from(&n.left, shared, other, missing);
+            if !n.is_tombstone() && Self::find_live(other, n.key).is_none() {
+                missing.push(n.key);
+            }
+            Self::keys_missing_from(&n.right, shared, other, missing);
+        }
+    }
+
+    /// Whether `other` holds every entry under `node`, skipping subtrees
+    /// whose hash is in `shared`.
+    fn contained_in(
+        node: &Option<Box<Node>>,
+        codec: Option<&ValueCodec>,
+        shared: &HashSet<HashType>,
+        other: &MerkleAvlTree,
+    ) -> bool {
+        let Some(n) = node else {
+            return true;
+        };
+        let same_value = |other_n: &Node| {
+            other.decode(&other_n.value) == ValueCodec::decode(codec, &n.value)
+        };
+        shared.contains(&n.hash)
+            || ((n.is_tombstone() || Self::find_live(&other.root, n.key).is_some_and(same_value))
+                && Self::contained_in(&n.left, codec, shared, other)
+                && Self::contained_in(&n.right, codec, shared, other))
+    }
+
+    /// Counts the live keys under `node` that `other` lacks, and with
+    /// `count_updates` also those it holds under a different value hash,
+    /// skipping subtrees whose hash is in `shared`.
+    fn count_changes(
+        node: &Option<Box<Node>>,
+        shared: &HashSet<HashType>,
+        other: &Option<Box<Node>>,
+        count_updates: bool,
+    ) -> usize {
+        let Some(n) = node else {
+            return 0;
+        };
+        if shared.contains(&n.hash) {
+            return 0;
+        }
+        let changed = !n.is_tombstone()
+            && match Self::find_live(other, n.key) {
+                None => true,
+                Some(other_n) => count_updates && other_n.value_hash != n.value_hash,
+            };
+        usize::from(changed)
+            + Self::count_changes(&n.left, shared, other, count_updates)
+            + Self::count_changes(&n.right, shared, other, count_updates)
+    }
+
+    fn find_mut(mut node: &mut Option<Box<Node>>, key: Key) -> Option<&mut Node> {
+        while let Some(n) = node {
+            match key.cmp(&n.key) {
+                Ordering::Less => node = &mut n.left,
+                Ordering::Greater => node = &mut n.right,
+                Ordering::Equal => return Some(n),
+            }
+        }
+        None
+    }
+
+    /// Recomputes the hash of every node on the search paths of `a` and
+    /// `b`, bottom up, each once.
+    fn rehash_paths(node: &mut Option<Box<Node>>, a: Key, b: Key) {
+        if let Some(n) = node {
+            if a < n.key || b < n.key {
+                Self::rehash_paths(&mut n.left, a, b);
+            }
+            if a > n.key || b > n.key {
+                Self::rehash_paths(&mut n.right, a, b);
+            }
+            Self::update_height_and_hash(n);
+        }
+    }
+
+    /// The link holding `key`, or the empty link where it would go.
+    fn find_link(mut node: &Option<Box<Node>>, key: Key) -> &Option<Box<Node>> {
+        while let Some(n) = node {
+            match key.cmp(&n.key) {
+                Ordering::Less => node = &n.left,
+                Ordering::Greater => node = &n.right,
+                Ordering::Equal => break,
+            }
+        }
+        node
+    }
+
+    fn find(node: &Option<Box<Node>>, key: Key) -> Option<&Node> {
+        let mut current = node;
+        while let Some(n) = current {
+            match key.cmp(&n.key) {
+                Ordering::Less => current = &n.left,
+                Ordering::Greater => current = &n.right,
+                Ordering::Equal => return Some(n),
+            }
+        }
+        None
+    }
+
+    /// `find`, treating a `soft_delete` tombstone as absent.
+    fn find_live(node: &Option<Box<Node>>, key: Key) -> Option<&Node> {
+        Self::find(node, key).filter(|n| !n.is_tombstone())
+    }
+
+    fn lookup(node: &Option<Box<Node>>, key: Key) -> Result<&Value, Error> {
+        if let Some(n) = node {
+            match key.cmp(&n.key) {
+                Ordering::Less => Self::lookup(&n.left, key),
+                Ordering::Greater => Self::lookup(&n.right, key),
+                Ordering::Equal if n.is_tombstone() => Err(Error::Deleted),
+                Ordering::Equal => Ok(&n.value),
+            }
+        } else {
+            Err(Error::NotFound)
+        }
+    }
+
+    fn generate_proof(node: &Option<Box<Node>>, key: Key) -> Result<ProofNode, Error> {
+        if let Some(n) = node {
+            match key.cmp(&n.key) {
+                Ordering::Less => {
+                    let left_proof = Self::generate_proof(&n.left, key)?;
+                    Ok(ProofNode::Left(
+                        n.key,
+                        n.value_hash,
+                        Box::new(left_proof),
+                        Self::hash_of(&n.right),
+                    ))
+                }
+                Ordering::Greater => {
+                    let right_proof = Self::generate_proof(&n.right, key)?;
+                    Ok(ProofNode::Right(
+                        n.key,
+                        n.value_hash,
+                        Self::hash_of(&n.left),
+                        Box::new(right_proof),
+                    ))
+                }
+                Ordering::Equal => Ok(Self::terminal_proof(n)),
+            }
+        } else {
+            Ok(ProofNode::Empty)
+        }
+    }
+}
+impl ProofNode {
+    /// Folds a trailing step into an `Empty` child into a single
+    /// `EmptyLeft`/`EmptyRight` node. The proof's hash is unchanged.
+    pub fn compact(self) -> ProofNode {
+        match self {
+            ProofNode::Left(key, value_hash, child_proof, right) => match *child_proof {
+                ProofNode::Empty => ProofNode::EmptyLeft(key, value_hash, right),
+                child_proof => {
+                    ProofNode::Left(key, value_hash, Box::new(child_proof.compact()), right)
+                }
+            },
+            ProofNode::Right(key, value_hash, left, child_proof) => match *child_proof {
+                ProofNode::Empty => ProofNode::EmptyRight(key, value_hash, left),
+                child_proof => {
+                    ProofNode::Right(key, value_hash, left, Box::new(child_proof.compact()))
+                }
+            },
+            proof => proof,
+        }
+    }
+
+    /// Serializes the proof as `PROOF_VERSION` followed by each level of
+    /// `proof_to_mpt_nodes`, root first, behind a 4-byte big-endian length.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![PROOF_VERSION];
+        for node in MerkleAvlTree::proof_to_mpt_nodes(self) {
+            bytes.extend_from_slice(&(node.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&node);
+        }
+        bytes
+    }
+
+    /// Decodes `to_bytes` output. A leading byte other than `PROOF_VERSION`
+    /// is `Error::UnsupportedProofVersion`, more levels than any tree's proof
+    /// has is `Error::ProofTooDeep`, and anything else that doesn't decode
+    /// is `Error::MalformedProof`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<ProofNode, Error> {
+        let mut reader = ByteReader::new(bytes);
+        if reader.u8()? != PROOF_VERSION {
+            return Err(Error::UnsupportedProofVersion);
+        }
+        let mut nodes = Vec::new();
+        while !reader.bytes.is_empty() {
+            nodes.push(reader.bytes()?.to_vec());
+        }
+        MerkleAvlTree::proof_from_mpt_nodes(&nodes)
+    }
+
+    /// Memory held by the proof: one `ProofNode` per level plus leaf value
+    /// bytes.
+    pub fn size_bytes(&self) -> usize {
+        let node = std::mem::size_of::<ProofNode>();
+        match self {
+            ProofNode::Left(_, _, child_proof, _) | ProofNode::Right(_, _, _, child_proof) => {
+                node + child_proof.size_bytes()
+            }
+            ProofNode::Leaf(_, value, _, _) => node + value.len(),
+            _ => node,
+        }
+    }
+
+    fn hash(&self, salt: &[u8]) -> HashType {
+        match self {
+            ProofNode::Left(key, value_hash, child_proof, right) => {
+                Node::compute_hash(key, *value_hash, child_proof.child_hash(salt), *right)
+            }
+            ProofNode::Right(key, value_hash, left, child_proof) => {
+                Node::compute_hash(key, *value_hash, *left, child_proof.child_hash(salt))
+            }
+            ProofNode::Leaf(key, value, left, right) => {
+                Node::compute_hash(key, Node::value_hash(salt, value), *left, *right)
+            }
+            ProofNode::Empty => EMPTY_HASH,
+            ProofNode::EmptyLeft(key, value_hash, right) => {
+                Node::compute_hash(key, *value_hash, None, *right)
+            }
+            ProofNode::EmptyRight(key, value_hash, left) => {
+                Node::compute_hash(key, *value_hash, *left, None)
+            }
+            ProofNode::Tombstone(key, left, right) => {
+                Node::compute_hash(key, Node::tombstone_hash(), *left, *right)
+            }
+            ProofNode::LeafHash(key, value_hash, left, right) => {
+                Node::compute_hash(key, *value_hash, *left, *right)
+            }
+        }
+    }
+
+    fn child_hash(&self, salt: &[u8]) -> Option<HashType> {
+        match self {
+            ProofNode::Empty => None,
+            _ => Some(self.hash(salt)),
+        }
+    }
+
+    /// Appends this level's encoding (see `proof_to_mpt_nodes`) and returns
+    /// the child level, if any.
+    fn encode_level(&self, out: &mut Vec<u8>) -> Option<&ProofNode> {
+        let put_hash = |out: &mut Vec<u8>, hash: &Option<HashType>| match hash {
+            Some(hash) => {
+                out.push(1);
+                out.extend_from_slice(&hash_to_bytes(*hash));
+            }
+            None => out.push(0),
+        };
+        match self {
+            ProofNode::Left(key, value_hash, child_proof, right) => {
+                out.push(0);
+                out.extend_from_slice(&key.to_be_bytes());
+                out.extend_from_slice(&hash_to_bytes(*value_hash));
+                put_hash(out, right);
+                Some(child_proof)
+            }
+            ProofNode::Right(key, value_hash, left, child_proof) => {
+                out.push(1);
+                out.extend_from_slice(&key.to_be_bytes());
+                out.extend_from_slice(&hash_to_bytes(*value_hash));
+                put_hash(out, left);
+                Some(child_proof)
+            }
+            ProofNode::Leaf(key, value, left, right) => {
+                out.push(2);
+                out.extend_from_slice(&key.to_be_bytes());
+                put_hash(out, left);
+                put_hash(out, right);
+                out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                out.extend_from_slice(value.as_bytes());
+                None
+            }
+            ProofNode::Empty => {
+                out.push(3);
+                None
+            }
+            ProofNode::EmptyLeft(key, value_hash, right) => {
+                out.push(4);
+                out.extend_from_slice(&key.to_be_bytes());
+                out.extend_from_slice(&hash_to_bytes(*value_hash));
+                put_hash(out, right);
+                None
+            }
+            ProofNode::EmptyRight(key, value_hash, left) => {
+                out.push(5);
+                out.extend_from_slice(&key.to_be_bytes());
+                out.extend_from_slice(&hash_to_bytes(*value_hash));
+                put_hash(out, left);
+                None
+            }
+            ProofNode::Tombstone(key, left, right) => {
+                out.push(6);
+                out.extend_from_slice(&key.to_be_bytes());
+                put_hash(out, left);
+                put_hash(out, right);
+                None
+            }
+            ProofNode::LeafHash(key, value_hash, left, right) => {
+                out.push(7);
+                out.extend_from_slice(&key.to_be_bytes());
+                out.extend_from_slice(&hash_to_bytes(*value_hash));
+                put_hash(out, left);
+                put_hash(out, right);
+                None
+            }
+        }
+    }
 
-#[derive(Debug, PartialEq, Clone)]
-pub enum Error {
-    NotFound,
-    InvalidProof,
-}
+    /// Decodes one level. `child` is the already decoded level below, which
+    /// `Left` and `Right` require and every other node forbids.
+    fn decode_level(
+        reader: &mut ByteReader<'_>,
+        child: Option<ProofNode>,
+    ) -> Result<ProofNode, Error> {
+        let tag = reader.u8()?;
+        let proof = match (tag, child) {
+            (0, Some(child)) => {
+                let (key, value_hash) = (reader.key()?, reader.hash()?);
+                ProofNode::Left(key, value_hash, Box::new(child), reader.opt_hash()?)
+            }
+            (1, Some(child)) => {
+                let (key, value_hash) = (reader.key()?, reader.hash()?);
+                ProofNode::Right(key, value_hash, reader.opt_hash()?, Box::new(child))
+            }
+            (2, None) => {
+                let key = reader.key()?;
+                let (left, right) = (reader.opt_hash()?, reader.opt_hash()?);
+                ProofNode::Leaf(key, reader.value()?, left, right)
+            }
+            (3, None) => ProofNode::Empty,
+            (4, None) => ProofNode::EmptyLeft(reader.key()?, reader.hash()?, reader.opt_hash()?),
+            (5, None) => ProofNode::EmptyRight(reader.key()?, reader.hash()?, reader.opt_hash()?),
+            (6, None) => {
+                let key = reader.key()?;
+                ProofNode::Tombstone(key, reader.opt_hash()?, reader.opt_hash()?)
+            }
+            (7, None) => {
+                let (key, value_hash) = (reader.key()?, reader.hash()?);
+                ProofNode::LeafHash(key, value_hash, reader.opt_hash()?, reader.opt_hash()?)
+            }
+            _ => return Err(Error::MalformedProof),
+        };
+        reader.finish()?;
+        Ok(proof)
+    }
 
-#[derive(Debug, PartialEq, Clone)]
-pub enum Operation {
-    Insert(Key, Value),
-    Delete(Key),
-    Lookup(Key),
-}
+    /// The key recorded at this level of the proof.
+    fn key(&self) -> Option<Key> {
+        match self {
+            ProofNode::Left(key, ..)
+            | ProofNode::Right(key, ..)
+            | ProofNode::Leaf(key, ..)
+            | ProofNode::EmptyLeft(key, ..)
+            | ProofNode::EmptyRight(key, ..)
+            | ProofNode::Tombstone(key, ..)
+            | ProofNode::LeafHash(key, ..) => Some(*key),
+            ProofNode::Empty => None,
+        }
+    }
 
-#[derive(Debug, PartialEq, Clone)]
-pub enum ProofNode {
-    Left(HashType, Box<ProofNode>),
-    Right(Box<ProofNode>, HashType),
-    Leaf(Key, Value),
-    Empty,
+    /// The keys along the path, each with whether the path turned left
+    /// there, and the node the path ends at.
+    fn steps(&self) -> (Vec<(Key, bool)>, &ProofNode) {
+        let mut steps = Vec::new();
+        let mut current = self;
+        loop {
+            match current {
+                ProofNode::Left(key, _, child_proof, _) => {
+                    steps.push((*key, true));
+                    current = child_proof;
+                }
+                ProofNode::Right(key, _, _, child_proof) => {
+                    steps.push((*key, false));
+                    current = child_proof;
+                }
+                ProofNode::EmptyLeft(key, _, _) => {
+                    steps.push((*key, true));
+                    return (steps, &EMPTY_PROOF);
+                }
+                ProofNode::EmptyRight(key, _, _) => {
+                    steps.push((*key, false));
+                    return (steps, &EMPTY_PROOF);
+                }
+                _ => return (steps, current),
+            }
+        }
+    }
+
+    /// Whether this is the path a search for `key` would take: every turn
+    /// agrees with `key`'s order, and a leaf at the end holds `key`.
+    fn follows(&self, key: Key) -> bool {
+        let (steps, terminal) = self.steps();
+        let on_path = steps
+            .iter()
+            .all(|&(step_key, went_left)| went_left == (key < step_key));
+        let ends_at_key = match terminal {
+            ProofNode::Leaf(leaf_key, ..)
+            | ProofNode::LeafHash(leaf_key, ..)
+            | ProofNode::Tombstone(leaf_key, ..) => *leaf_key == key,
+            _ => true,
+        };
+        on_path && ends_at_key
+    }
+
+    /// Checks `neighbor` is the entry directly before (`before == true`) or
+    /// after `target`. If the target has a child on that side the neighbor is
+    /// the innermost node of that subtree; otherwise it is the last ancestor
+    /// the path turned away from.
+    fn check_neighbor(
+        target: &ProofNode,
+        neighbor: Option<&ProofNode>,
+        root_hash: RootHash,
+        before: bool,
+    ) -> Result<(), Error> {
+        let (steps, _) = target.steps();
+        let inner_child = match target.terminal_node() {
+            Some((_, left, right)) => {
+                if before {
+                    left
+                } else {
+                    right
+                }
+            }
+            None => None,
+        };
+        let neighbor_leaf = |proof: &ProofNode| match proof.terminal_node() {
+            Some((key, left, right)) if proof.hash(NO_SALT) == root_hash.0 => {
+                let outer_child = if before { right } else { left };
+                Some((proof.steps().0, key, outer_child))
+            }
+            _ => None,
+        };
+
+        if inner_child.is_some() {
+            let target_key = match target.terminal_node() {
+                Some((key, _, _)) => key,
+                None => return Err(Error::InvalidProof),
+            };
+            let (neighbor_steps, _, outer_child) = neighbor
+                .and_then(neighbor_leaf)
+                .ok_or(Error::InvalidProof)?;
+            let descends = neighbor_steps.len() > steps.len()
+                && neighbor_steps[..steps.len()] == steps[..]
+                && neighbor_steps[steps.len()] == (target_key, before)
+                && neighbor_steps[steps.len() + 1..]
+                    .iter()
+                    .all(|&(_, went_left)| went_left != before);
+            return if descends && outer_child.is_none() {
+                Ok(())
+            } else {
+                Err(Error::InvalidProof)
+            };
+        }
+
+        let expected = steps
+            .iter()
+            .rev()
+            .find(|&&(_, went_left)| went_left != before)
+            .map(|&(key, _)| key);
+        match (expected, neighbor) {
+            (None, None) => Ok(()),
+            (Some(expected), Some(proof)) => match neighbor_leaf(proof) {
+                Some((_, key, _)) if key == expected => Ok(()),
+                _ => Err(Error::InvalidProof),
+            },
+            _ => Err(Error::InvalidProof),
+        }
+    }
+
+    /// The key and child hashes of the node the proof ends at, a live leaf
+    /// or a tombstone.
+    fn terminal_node(&self) -> Option<(Key, Option<HashType>, Option<HashType>)> {
+        match self.steps().1 {
+            ProofNode::Leaf(key, _, left, right) | ProofNode::Tombstone(key, left, right) => {
+                Some((*key, *left, *right))
+            }
+            _ => None,
+        }
+    }
+
+    fn key_value(&self) -> Option<(&Key, &Value)> {
+        match self {
+            ProofNode::Left(_, _, child_proof, _) | ProofNode::Right(_, _, _, child_proof) => {
+                child_proof.key_value()
+            }
+            ProofNode::Leaf(key, value, _, _) => Some((key, value)),
+            _ => None,
+        }
+    }
+
+    fn with_value(&self, new_value: &Value) -> ProofNode {
+        match self {
+            ProofNode::Left(key, value_hash, child_proof, right) => ProofNode::Left(
+                *key,
+                *value_hash,
+                Box::new(child_proof.with_value(new_value)),
+                *right,
+            ),
+            ProofNode::Right(key, value_hash, left, child_proof) => ProofNode::Right(
+                *key,
+                *value_hash,
+                *left,
+                Box::new(child_proof.with_value(new_value)),
+            ),
+            ProofNode::Leaf(key, _, left, right) => {
+                ProofNode::Leaf(*key, new_value.clone(), *left, *right)
+            }
+            proof => proof.clone(),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Clone)]
-pub struct MerkleAvlTree {
-    root: Option<Box<Node>>,
+/// A subtree as `next_root` sees it: untouched and borrowed from the tree,
+/// or rebuilt along the path of the operation being simulated. The AVL
+/// steps mirror `Node::insert`, `Node::delete` and `Node::balance`.
+enum Shadow<'a> {
+    Empty,
+    Shared(&'a Node),
+    Owned(Box<ShadowNode<'a>>),
 }
 
-#[derive(Debug, PartialEq, Clone)]
-pub struct Node {
+struct ShadowNode<'a> {
     key: Key,
-    value: Value,
-    hash: HashType,
+    value_hash: HashType,
     height: i32,
-    left: Option<Box<Node>>,
-    right: Option<Box<Node>>,
+    hash: HashType,
+    left: Shadow<'a>,
+    right: Shadow<'a>,
 }
 
-impl MerkleAvlTree {
-    pub fn new() -> MerkleAvlTree {
-        MerkleAvlTree { root: None }
+impl<'a> Shadow<'a> {
+    fn of(link: &'a Option<Box<Node>>) -> Shadow<'a> {
+        link.as_deref().map_or(Shadow::Empty, Shadow::Shared)
     }
 
-    pub fn insert(&mut self, key: Key, value: Value) {
-        self.root = Node::insert(self.root.take(), key, value);
+    fn take(&mut self) -> Shadow<'a> {
+        std::mem::replace(self, Shadow::Empty)
     }
 
-    pub fn delete(&mut self, key: Key) -> Result<(), Error> {
-        let (new_root, deleted) = Node::delete(self.root.take(), key)?;
-        self.root = new_root;
-        if deleted.is_some() {
-            Ok(())
-        } else {
-            Err(Error::NotFound)
+    fn height(&self) -> i32 {
+        match self {
+            Shadow::Empty => 0,
+            Shadow::Shared(n) => n.height,
+            Shadow::Owned(n) => n.height,
         }
     }
 
-    pub fn lookup(&self, key: Key) -> Result<&Value, Error> {
-        Node::lookup(&self.root, key)
+    fn hash(&self) -> Option<HashType> {
+        match self {
+            Shadow::Empty => None,
+            Shadow::Shared(n) => Some(n.hash),
+            Shadow::Owned(n) => Some(n.hash),
+        }
     }
 
-    pub fn root_hash(&self) -> Option<HashType> {
-        self.root.as_ref().map(|node| node.hash)
+    fn balance_factor(&self) -> i32 {
+        match self {
+            Shadow::Empty => 0,
+            Shadow::Shared(n) => Node::height(&n.right) - Node::height(&n.left),
+            Shadow::Owned(n) => n.right.height() - n.left.height(),
+        }
     }
 
-    pub fn generate_proof(&self, key: Key) -> Result<ProofNode, Error> {
-        Node::generate_proof(&self.root, key)
+    /// Takes the top node so it can change; its children stay borrowed.
+    fn open(self) -> Option<Box<ShadowNode<'a>>> {
+        match self {
+            Shadow::Empty => None,
+            Shadow::Shared(n) => Some(Box::new(ShadowNode {
+                key: n.key,
+                value_hash: n.value_hash,
+                height: n.height,
+                hash: n.hash,
+                left: Shadow::of(&n.left),
+                right: Shadow::of(&n.right),
+            })),
+            Shadow::Owned(n) => Some(n),
+        }
     }
 
-    pub fn verify_proof(proof: &ProofNode, root_hash: HashType) -> Result<(&Key, &Value), Error> {
-        if proof.hash() == root_hash {
-            proof.key_value().ok_or(Error::InvalidProof)
-        } else {
-            Err(Error::InvalidProof)
+    fn insert(self, key: Key, value_hash: HashType) -> Shadow<'a> {
+        let Some(mut n) = self.open() else {
+            return Shadow::Owned(ShadowNode::leaf(key, value_hash));
+        };
+        match key.cmp(&n.key) {
+            Ordering::Less => n.left = n.left.take().insert(key, value_hash),
+            Ordering::Greater => n.right = n.right.take().insert(key, value_hash),
+            Ordering::Equal => n.value_hash = value_hash,
+        }
+        n.update();
+        ShadowNode::balance(n)
+    }
+
+    fn delete(self, key: Key) -> Result<Shadow<'a>, Error> {
+        let mut n = self.open().ok_or(Error::NotFound)?;
+        match key.cmp(&n.key) {
+            Ordering::Less => n.left = n.left.take().delete(key)?,
+            Ordering::Greater => n.right = n.right.take().delete(key)?,
+            Ordering::Equal => match (n.left.take(), n.right.take()) {
+                (Shadow::Empty, right) => return Ok(right),
+                (left, Shadow::Empty) => return Ok(left),
+                (left, right) => {
+                    let (right, min) = right.open().expect("non-empty").delete_min();
+                    n.key = min.key;
+                    n.value_hash = min.value_hash;
+                    n.left = left;
+                    n.right = right;
+                }
+            },
         }
+        n.update();
+        Ok(ShadowNode::balance(n))
     }
 }
 
-impl Node {
-    fn new(key: Key, value: Value) -> Box<Node> {
-        Box::new(Node {
+impl<'a> ShadowNode<'a> {
+    fn leaf(key: Key, value_hash: HashType) -> Box<ShadowNode<'a>> {
+        Box::new(ShadowNode {
             key,
-            value: value.clone(),
-            hash: Self::compute_hash(&key, &value),
+            value_hash,
             height: 1,
-            left: None,
-            right: None,
+            hash: Node::compute_hash(&key, value_hash, None, None),
+            left: Shadow::Empty,
+            right: Shadow::Empty,
         })
     }
 
-    fn compute_hash<K: Hash, V: Hash>(key: &K, value: &V) -> HashType {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        value.hash(&mut hasher);
-        hasher.finish()
+    fn update(&mut self) {
+        self.height = 1 + std::cmp::max(self.left.height(), self.right.height());
+        self.hash =
+            Node::compute_hash(&self.key, self.value_hash, self.left.hash(), self.right.hash());
     }
 
-    fn height(node: &Option<Box<Node>>) -> i32 {
-        node.as_ref().map_or(0, |n| n.height)
+    fn rotate_left(mut self: Box<Self>) -> Box<ShadowNode<'a>> {
+        let mut new_root = self.right.take().open().expect("right child");
+        self.right = new_root.left.take();
+        self.update();
+        new_root.left = Shadow::Owned(self);
+        new_root.update();
+        new_root
     }
 
-    fn update_height_and_hash(node: &mut Box<Node>) {
-        node.height = 1 + std::cmp::max(Self::height(&node.left), Self::height(&node.right));
-        let mut hasher = DefaultHasher::new();
-        node.key.hash(&mut hasher);
-        node.value.hash(&mut hasher);
-        if let Some(ref left) = node.left {
-            left.hash.hash(&mut hasher);
-        }
-        if let Some(ref right) = node.right {
-            right.hash.hash(&mut hasher);
+    fn rotate_right(mut self: Box<Self>) -> Box<ShadowNode<'a>> {
+        let mut new_root = self.left.take().open().expect("left child");
+        self.left = new_root.right.take();
+        self.update();
+        new_root.right = Shadow::Owned(self);
+        new_root.update();
+        new_root
+    }
+
+    fn balance(mut n: Box<ShadowNode<'a>>) -> Shadow<'a> {
+        let bf = n.right.height() - n.left.height();
+        if bf > 1 {
+            if n.right.balance_factor() < 0 {
+                let right = n.right.take().open().expect("right child");
+                n.right = Shadow::Owned(right.rotate_right());
+            }
+            return Shadow::Owned(n.rotate_left());
+        } else if bf < -1 {
+            if n.left.balance_factor() > 0 {
+                let left = n.left.take().open().expect("left child");
+                n.left = Shadow::Owned(left.rotate_left());
+            }
+            return Shadow::Owned(n.rotate_right());
         }
-        node.hash = hasher.finish();
+        Shadow::Owned(n)
     }
 
-    fn balance_factor(node: &Option<Box<Node>>) -> i32 {
-        Self::height(&node.as_ref().unwrap().right) - Self::height(&node.as_ref().unwrap().left)
+    fn delete_min(mut self: Box<Self>) -> (Shadow<'a>, Box<ShadowNode<'a>>) {
+        match self.left.take().open() {
+            Some(left) => {
+                let (new_left, min) = left.delete_min();
+                self.left = new_left;
+                self.update();
+                (ShadowNode::balance(self), min)
+            }
+            None => (self.right.take(), self),
+        }
     }
+}
 
-    fn rotate_left(mut node: Box<Node>) -> Box<Node> {
-        let mut new_root = node.right.take().unwrap();
-        node.right = new_root.left.take();
-        new_root.left = Some(node);
+/// Cursor over an encoded proof node; running short is a malformed proof.
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
 
-        Self::update_height_and_hash(&mut new_root.left.as_mut().unwrap());
-        Self::update_height_and_hash(&mut new_root);
+impl<'a> ByteReader<'a> {
+    fn new(bytes: &'a [u8]) -> ByteReader<'a> {
+        ByteReader { bytes }
+    }
 
-        new_root
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        if self.bytes.len() < N {
+            return Err(Error::MalformedProof);
+        }
+        let (head, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(head.try_into().expect("split at N"))
     }
 
-    fn rotate_right(mut node: Box<Node>) -> Box<Node> {
-        let mut new_root = node.left.take().unwrap();
-        node.left = new_root.right.take();
-        new_root.right = Some(node);
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take::<1>()?[0])
+    }
 
-        Self::update_height_and_hash(&mut new_root.right.as_mut().unwrap());
-        Self::update_height_and_hash(&mut new_root);
+    fn key(&mut self) -> Result<Key, Error> {
+        Ok(Key::from_be_bytes(self.take()?))
+    }
 
-        new_root
+    fn hash(&mut self) -> Result<HashType, Error> {
+        Ok(hash_from_bytes(self.take()?))
     }
 
-    fn balance(node: Option<Box<Node>>) -> Option<Box<Node>> {
-        if let Some(mut n) = node {
-            let bf = Self::balance_factor(&Some(n.clone()));
-            if bf > 1 {
-                if Self::balance_factor(&n.right) < 0 {
-                    n.right = Some(Self::rotate_right(n.right.take().unwrap()));
-                }
-                return Some(Self::rotate_left(n));
-            } else if bf < -1 {
-                if Self::balance_factor(&n.left) > 0 {
-                    n.left = Some(Self::rotate_left(n.left.take().unwrap()));
-                }
-                return Some(Self::rotate_right(n));
-            }
-            Some(n)
-        } else {
-            None
+    fn opt_hash(&mut self) -> Result<Option<HashType>, Error> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.hash()?)),
+            _ => Err(Error::MalformedProof),
         }
     }
 
-    fn insert(node: Option<Box<Node>>, key: Key, value: Value) -> Option<Box<Node>> {
-        let node = if let Some(mut n) = node {
-            match key.cmp(&n.key) {
-                Ordering::Less => {
-                    n.left = Self::insert(n.left.take(), key, value);
-                }
-                Ordering::Greater => {
-                    n.right = Self::insert(n.right.take(), key, value);
-                }
-                Ordering::Equal => {
-                    n.value = value;
-                }
-            }
-            Self::update_height_and_hash(&mut n);
-            Self::balance(Some(n))
-        } else {
-            Some(Self::new(key, value))
-        };
-        node
+    /// A 4-byte big-endian length followed by that many bytes.
+    fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = u32::from_be_bytes(self.take()?) as usize;
+        if self.bytes.len() < len {
+            return Err(Error::MalformedProof);
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
     }
 
-    fn delete(
-        node: Option<Box<Node>>,
-        key: Key,
-    ) -> Result<(Option<Box<Node>>, Option<Box<Node>>), Error> {
-        if let Some(mut n) = node {
-            let deleted: Option<Box<Node>>;
-            match key.cmp(&n.key) {
-                Ordering::Less => {
-                    let (new_left, del) = Self::delete(n.left.take(), key)?;
-                    n.left = new_left;
-                    deleted = del;
-                }
-                Ordering::Greater => {
-                    let (new_right, del) = Self::delete(n.right.take(), key)?;
-                    n.right = new_right;
-                    deleted = del;
-                }
-                Ordering::Equal => {
-                    deleted = Some(n.clone());
-                    if n.left.is_none() {
-                        return Ok((n.right.take(), deleted));
-                    } else if n.right.is_none() {
-                        return Ok((n.left.take(), deleted));
-                    } else {
-                        let (new_right, min_right) = Self::delete_min(n.right.take().unwrap());
-                        n.key = min_right.key;
-                        n.value = min_right.value;
-                        n.right = new_right;
-                    }
-                }
-            }
-            Self::update_height_and_hash(&mut n);
-            Ok((Self::balance(Some(n)), deleted))
-        } else {
-            Err(Error::NotFound)
-        }
+    fn value(&mut self) -> Result<Value, Error> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| Error::MalformedProof)
     }
 
-    fn delete_min(mut node: Box<Node>) -> (Option<Box<Node>>, Box<Node>) {
-        if let Some(left) = node.left.take() {
-            let (new_left, min_node) = Self::delete_min(left);
-            node.left = new_left;
-            Self::update_height_and_hash(&mut node);
-            (Self::balance(Some(node)), min_node)
+    fn finish(&self) -> Result<(), Error> {
+        if self.bytes.is_empty() {
+            Ok(())
         } else {
-            (node.right.take(), node)
+            Err(Error::MalformedProof)
         }
     }
+}
 
-    fn lookup<'a>(node: &'a Option<Box<Node>>, key: Key) -> Result<&'a Value, Error> {
-        if let Some(n) = node {
-            match key.cmp(&n.key) {
-                Ordering::Less => Self::lookup(&n.left, key),
-                Ordering::Greater => Self::lookup(&n.right, key),
-                Ordering::Equal => Ok(&n.value),
-            }
-        } else {
-            Err(Error::NotFound)
+/// In-order iterator over a tree's entries, yielding values as inserted and
+/// skipping `soft_delete` tombstones.
+pub struct Iter<'a> {
+    stack: Vec<&'a Node>,
+    codec: Option<&'a ValueCodec>,
+}
+
+impl<'a> Iter<'a> {
+    fn new(root: &'a Option<Box<Node>>, codec: Option<&'a ValueCodec>) -> Iter<'a> {
+        let mut iter = Iter {
+            stack: Vec::new(),
+            codec,
+        };
+        iter.push_left(root);
+        iter
+    }
+
+    fn push_left(&mut self, mut node: &'a Option<Box<Node>>) {
+        while let Some(n) = node {
+            self.stack.push(n);
+            node = &n.left;
         }
     }
+}
 
-    fn generate_proof(node: &Option<Box<Node>>, key: Key) -> Result<ProofNode, Error> {
-        if let Some(n) = node {
-            match key.cmp(&n.key) {
-                Ordering::Less => {
-                    let left_proof = Self::generate_proof(&n.left, key)?;
-                    Ok(ProofNode::Left(n.hash, Box::new(left_proof)))
-                }
-                Ordering::Greater => {
-                    let right_proof = Self::generate_proof(&n.right, key)?;
-                    Ok(ProofNode::Right(Box::new(right_proof), n.hash))
-                }
-                Ordering::Equal => Ok(ProofNode::Leaf(n.key, n.value.clone())),
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Key, Cow<'a, Value>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let node = self.stack.pop()?;
+            self.push_left(&node.right);
+            if !node.is_tombstone() {
+                return Some((&node.key, ValueCodec::decode(self.codec, &node.value)));
             }
-        } else {
-            Ok(ProofNode::Empty)
         }
     }
 }
-impl ProofNode {
-    fn hash(&self) -> HashType {
-        match self {
-            ProofNode::Left(node_hash, child_proof) => {
-                let mut hasher = DefaultHasher::new();
-                node_hash.hash(&mut hasher);
-                child_proof.hash().hash(&mut hasher);
-                hasher.finish()
-            }
-            ProofNode::Right(child_proof, node_hash) => {
-                let mut hasher = DefaultHasher::new();
-                child_proof.hash().hash(&mut hasher);
-                node_hash.hash(&mut hasher);
-                hasher.finish()
-            }
-            ProofNode::Leaf(key, value) => {
-                let mut hasher = DefaultHasher::new();
-                key.hash(&mut hasher);
-                value.hash(&mut hasher);
-                hasher.finish()
+
+/// Iterator over the entries in `[lo, hi]`, ascending or descending.
+struct RangeIter<'a> {
+    stack: Vec<&'a Node>,
+    codec: Option<&'a ValueCodec>,
+    lo: Key,
+    hi: Key,
+    rev: bool,
+}
+
+impl<'a> RangeIter<'a> {
+    fn new(
+        root: &'a Option<Box<Node>>,
+        codec: Option<&'a ValueCodec>,
+        lo: Key,
+        hi: Key,
+        rev: bool,
+    ) -> RangeIter<'a> {
+        let mut iter = RangeIter {
+            stack: Vec::new(),
+            codec,
+            lo,
+            hi,
+            rev,
+        };
+        iter.descend(root);
+        iter
+    }
+
+    /// Pushes the path toward the next entry in iteration order, passing
+    /// over nodes that fall before the range.
+    fn descend(&mut self, mut node: &'a Option<Box<Node>>) {
+        while let Some(n) = node {
+            if !self.rev && n.key < self.lo {
+                node = &n.right;
+            } else if self.rev && n.key > self.hi {
+                node = &n.left;
+            } else {
+                self.stack.push(n);
+                node = if self.rev { &n.right } else { &n.left };
             }
-            ProofNode::Empty => 0,
         }
     }
+}
 
-    fn key_value(&self) -> Option<(&Key, &Value)> {
-        match self {
-            ProofNode::Leaf(key, value) => Some((key, value)),
-            _ => None,
+impl<'a> Iterator for RangeIter<'a> {
+    type Item = (&'a Key, Cow<'a, Value>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let node = self.stack.pop()?;
+            if node.key < self.lo || node.key > self.hi {
+                self.stack.clear();
+                return None;
+            }
+            self.descend(if self.rev { &node.left } else { &node.right });
+            if !node.is_tombstone() {
+                return Some((&node.key, ValueCodec::decode(self.codec, &node.value)));
+            }
         }
     }
 }
+
+#[cfg(test)]
+#[path = "node_test.rs"]
+mod node_test;
@@ -1,22 +1,27 @@
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use crate::node::*;
+
+    /// An arbitrary hash at the configured width.
+    fn fake_hash(byte: u8) -> HashType {
+        hash_from_bytes([byte; HASH_BYTES])
+    }
 
     #[test]
     fn test_insert_lookup() {
         let mut tree = MerkleAvlTree::new();
-        tree.insert(10, "value10".to_string());
-        tree.insert(20, "value20".to_string());
+        tree.insert(10, "value10".to_string()).unwrap();
+        tree.insert(20, "value20".to_string()).unwrap();
 
-        assert_eq!(tree.lookup(10).unwrap(), "value10");
-        assert_eq!(tree.lookup(20).unwrap(), "value20");
+        assert_eq!(*tree.lookup(10).unwrap(), "value10");
+        assert_eq!(*tree.lookup(20).unwrap(), "value20");
     }
 
     #[test]
     fn test_delete() {
         let mut tree = MerkleAvlTree::new();
-        tree.insert(10, "value10".to_string());
-        tree.insert(20, "value20".to_string());
+        tree.insert(10, "value10".to_string()).unwrap();
+        tree.insert(20, "value20".to_string()).unwrap();
 
         tree.delete(10).unwrap();
         assert!(tree.lookup(10).is_err());
@@ -25,26 +30,2526 @@ mod tests {
     #[test]
     fn test_proof_generation_and_verification() {
         let mut tree = MerkleAvlTree::new();
-        tree.insert(10, "value10".to_string());
-        tree.insert(20, "value20".to_string());
-        tree.insert(5, "value5".to_string());
+        tree.insert(10, "value10".to_string()).unwrap();
+        tree.insert(20, "value20".to_string()).unwrap();
+        tree.insert(5, "value5".to_string()).unwrap();
 
         let proof = tree.generate_proof(10).unwrap();
         let root_hash = tree.root_hash().unwrap();
 
-        assert!(MerkleAvlTree::verify_proof(&proof, root_hash));
+        assert!(MerkleAvlTree::verify_proof(&proof, root_hash).is_ok());
     }
 
     #[test]
     fn test_failed_proof_verification() {
         let mut tree = MerkleAvlTree::new();
-        tree.insert(10, "value10".to_string());
-        tree.insert(20, "value20".to_string());
-        tree.insert(5, "value5".to_string());
+        tree.insert(10, "value10".to_string()).unwrap();
+        tree.insert(20, "value20".to_string()).unwrap();
+        tree.insert(5, "value5".to_string()).unwrap();
 
         let proof = tree.generate_proof(10).unwrap();
-        let fake_root_hash = 123456789;
+        let fake_root_hash = RootHash::from(fake_hash(9));
+
+        assert!(MerkleAvlTree::verify_proof(&proof, fake_root_hash).is_err());
+    }
+
+    #[test]
+    fn test_updated_root() {
+        let mut tree = MerkleAvlTree::new();
+        for key in [10, 20, 5, 15, 25, 1] {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+
+        let proof = tree.generate_proof(15).unwrap();
+        let old_root = tree.root_hash().unwrap();
+        let new_value = "updated15".to_string();
+        let predicted = MerkleAvlTree::updated_root(&proof, &new_value, old_root).unwrap();
+
+        tree.insert(15, new_value.clone()).unwrap();
+        assert_eq!(predicted, tree.root_hash().unwrap());
+        assert_eq!(
+            MerkleAvlTree::updated_root(&proof, &new_value, RootHash::from(fake_hash(9))),
+            Err(Error::InvalidProof)
+        );
+    }
+
+    #[test]
+    fn test_merge_join() {
+        let mut left = MerkleAvlTree::new();
+        let mut right = MerkleAvlTree::new();
+        for key in [1, 3, 5, 7] {
+            left.insert(key, format!("left{}", key)).unwrap();
+        }
+        for key in [3, 4, 7, 9] {
+            right.insert(key, format!("right{}", key)).unwrap();
+        }
+
+        let items: Vec<MergeItem> = left.merge_join(&right).collect();
+        let (l3, r3) = (Cow::Owned("left3".to_string()), Cow::Owned("right3".to_string()));
+        let (l7, r7) = (Cow::Owned("left7".to_string()), Cow::Owned("right7".to_string()));
+        assert_eq!(
+            items,
+            vec![
+                MergeItem::OnlyLeft(&1, Cow::Owned("left1".to_string())),
+                MergeItem::Both(&3, l3, r3),
+                MergeItem::OnlyRight(&4, Cow::Owned("right4".to_string())),
+                MergeItem::OnlyLeft(&5, Cow::Owned("left5".to_string())),
+                MergeItem::Both(&7, l7, r7),
+                MergeItem::OnlyRight(&9, Cow::Owned("right9".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_out_of_line_root_matches_inline() {
+        let mut inline = MerkleAvlTree::new();
+        let mut out_of_line = OutOfLineTree::new();
+        let mut store = Vec::new();
+        for key in [10, 20, 5, 15, 25] {
+            let value = format!("value{}", key);
+            inline.insert(key, value.clone()).unwrap();
+            out_of_line.insert(key, &value, store.len() as Handle);
+            store.push(value);
+        }
+
+        assert_eq!(out_of_line.root_hash(), inline.root_hash());
+        let fetched = out_of_line.fetch(15, |handle| store[handle as usize].clone());
+        assert_eq!(fetched.unwrap(), "value15");
+        assert_eq!(
+            out_of_line.fetch(15, |_| "tampered".to_string()),
+            Err(Error::InvalidProof)
+        );
+
+        inline.delete(5).unwrap();
+        assert_eq!(out_of_line.delete(5), Ok(2));
+        assert_eq!(out_of_line.root_hash(), inline.root_hash());
+    }
+
+    #[test]
+    fn test_lca() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 1..=7 {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+
+        // Sequential inserts settle into 4 / (2, 6) / (1, 3, 5, 7).
+        assert_eq!(tree.lca(1, 3), Some((&2, Cow::Owned("value2".to_string()))));
+        assert_eq!(tree.lca(1, 7), Some((&4, Cow::Owned("value4".to_string()))));
+        assert_eq!(tree.lca(5, 6), Some((&6, Cow::Owned("value6".to_string()))));
+        assert_eq!(tree.lca(1, 100), None);
+    }
+
+    #[test]
+    fn test_salted_roots_differ_and_verify() {
+        let mut alice = MerkleAvlTree::with_salt(b"alice");
+        let mut bob = MerkleAvlTree::with_salt(b"bob");
+        for key in [10, 20, 5, 15] {
+            alice.insert(key, format!("value{}", key)).unwrap();
+            bob.insert(key, format!("value{}", key)).unwrap();
+        }
+
+        let alice_root = alice.root_hash().unwrap();
+        let bob_root = bob.root_hash().unwrap();
+        assert_ne!(alice_root, bob_root);
+
+        let alice_proof = alice.generate_proof(15).unwrap();
+        let bob_proof = bob.generate_proof(15).unwrap();
+        assert!(MerkleAvlTree::verify_proof_with_salt(&alice_proof, alice_root, b"alice").is_ok());
+        assert!(MerkleAvlTree::verify_proof_with_salt(&bob_proof, bob_root, b"bob").is_ok());
+        assert!(MerkleAvlTree::verify_proof_with_salt(&alice_proof, alice_root, b"bob").is_err());
+        assert!(MerkleAvlTree::verify_proof(&alice_proof, alice_root).is_err());
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut tree = MerkleAvlTree::with_lru_capacity(3);
+        for key in [1, 2, 3] {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+        tree.lookup(1).unwrap();
+        tree.insert(3, "value3b".to_string()).unwrap();
+        tree.insert(4, "value4".to_string()).unwrap();
+
+        assert_eq!(tree.lookup(2), Err(Error::NotFound));
+        assert_eq!(*tree.lookup(1).unwrap(), "value1");
+        assert_eq!(*tree.lookup(3).unwrap(), "value3b");
+        assert_eq!(*tree.lookup(4).unwrap(), "value4");
+
+        let mut expected = MerkleAvlTree::new();
+        for key in [1, 2, 3] {
+            expected.insert(key, format!("value{}", key)).unwrap();
+        }
+        expected.insert(3, "value3b".to_string()).unwrap();
+        expected.delete(2).unwrap();
+        expected.insert(4, "value4".to_string()).unwrap();
+        assert_eq!(tree.root_hash(), expected.root_hash());
+    }
+
+    #[test]
+    fn test_neighbor_proof() {
+        let mut tree = MerkleAvlTree::new();
+        for key in [10, 20, 30, 40, 50, 60, 70] {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+        let root_hash = tree.root_hash().unwrap();
+
+        let present = tree.neighbor_proof(40).unwrap();
+        assert!(MerkleAvlTree::verify_neighbor_proof(&present, root_hash).is_ok());
+
+        let absent = tree.neighbor_proof(45).unwrap();
+        assert_eq!(absent.target.steps().1, &ProofNode::Empty);
+        assert!(MerkleAvlTree::verify_neighbor_proof(&absent, root_hash).is_ok());
+
+        let edge = tree.neighbor_proof(5).unwrap();
+        assert!(edge.predecessor.is_none());
+        assert!(MerkleAvlTree::verify_neighbor_proof(&edge, root_hash).is_ok());
+
+        // Claiming 30 sits right after 45 skips over 40.
+        let mut skipped = absent.clone();
+        skipped.predecessor = Some(tree.generate_proof(30).unwrap());
+        assert_eq!(
+            MerkleAvlTree::verify_neighbor_proof(&skipped, root_hash),
+            Err(Error::InvalidProof)
+        );
+    }
+
+    #[test]
+    fn test_empty_vs_not_found() {
+        let mut tree = MerkleAvlTree::new();
+        assert_eq!(tree.lookup(10), Err(Error::Empty));
+        assert_eq!(tree.delete(10), Err(Error::Empty));
+        assert_eq!(tree.generate_proof(10), Err(Error::Empty));
+
+        tree.insert(20, "value20".to_string()).unwrap();
+        tree.insert(30, "value30".to_string()).unwrap();
+        let root_hash = tree.root_hash();
+        assert_eq!(tree.lookup(10), Err(Error::NotFound));
+        assert_eq!(tree.delete(10), Err(Error::NotFound));
+        assert_eq!(tree.root_hash(), root_hash);
+        assert_eq!(*tree.lookup(30).unwrap(), "value30");
+    }
+
+    #[test]
+    fn test_compact_absence_proof() {
+        let mut tree = MerkleAvlTree::new();
+        for key in [10, 20, 30, 40, 50, 60, 70] {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+        let root_hash = tree.root_hash().unwrap();
+
+        let proof = tree.generate_proof(45).unwrap();
+        let compacted = proof.clone().compact();
+        assert!(compacted.size_bytes() < proof.size_bytes());
+        assert_eq!(RootHash::from(compacted.hash(NO_SALT)), root_hash);
+        assert_eq!(compacted.hash(NO_SALT), proof.hash(NO_SALT));
+        assert_eq!(
+            MerkleAvlTree::verify_proof(&compacted, root_hash),
+            MerkleAvlTree::verify_proof(&proof, root_hash)
+        );
+
+        let mut bundle = tree.neighbor_proof(45).unwrap();
+        bundle.target = bundle.target.compact();
+        assert!(MerkleAvlTree::verify_neighbor_proof(&bundle, root_hash).is_ok());
+
+        let membership = tree.generate_proof(50).unwrap();
+        assert_eq!(membership.clone().compact(), membership);
+    }
+
+    #[test]
+    fn test_insert_with_proof() {
+        let mut tree = MerkleAvlTree::new();
+        for key in [10, 20, 5, 15, 25, 1, 30] {
+            let value = format!("value{}", key);
+            let proof = tree.insert_with_proof(key, value.clone()).unwrap();
+            let root_hash = tree.root_hash().unwrap();
+            assert_eq!(
+                MerkleAvlTree::verify_proof(&proof, root_hash),
+                Ok((&key, &value))
+            );
+        }
+    }
+
+    #[test]
+    fn test_shrink_values() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..10 {
+            let mut value = String::with_capacity(1024);
+            value.push_str(&format!("value{}", key));
+            tree.insert(key, value).unwrap();
+        }
+        let root_hash = tree.root_hash();
+        let before = tree.memory_usage();
+
+        tree.shrink_values();
+        assert!(tree.memory_usage() < before);
+        assert_eq!(tree.root_hash(), root_hash);
+        assert_eq!(*tree.lookup(3).unwrap(), "value3");
+    }
+
+    #[test]
+    fn test_same_subtree() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 1..=7 {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+
+        // 4 / (2, 6) / (1, 3, 5, 7)
+        assert_eq!(tree.same_subtree(1, 3, 2), Some(true));
+        assert_eq!(tree.same_subtree(2, 3, 2), Some(true));
+        assert_eq!(tree.same_subtree(1, 5, 2), Some(false));
+        assert_eq!(tree.same_subtree(1, 7, 4), Some(true));
+        assert_eq!(tree.same_subtree(1, 3, 10), None);
+    }
+
+    #[test]
+    fn test_verify_proof_strict() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 1..=7 {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+        let root_hash = tree.root_hash().unwrap();
+        let proof = tree.generate_proof(3).unwrap();
+        assert_eq!(
+            MerkleAvlTree::verify_proof_strict(&proof, root_hash),
+            Ok((&3, &"value3".to_string()))
+        );
+
+        // An extra level claiming 3 sits left of 2.
+        let padded = ProofNode::Left(2, EMPTY_HASH, Box::new(proof.clone()), None);
+        assert_eq!(
+            MerkleAvlTree::verify_proof_strict(&padded, root_hash),
+            Err(Error::MalformedProof)
+        );
+
+        // The path stops at an empty position before reaching a leaf: shaped
+        // like an absence proof, but not one for this root.
+        let truncated = match proof {
+            ProofNode::Left(key, value_hash, _, right) => {
+                ProofNode::Left(key, value_hash, Box::new(ProofNode::Empty), right)
+            }
+            _ => panic!("3 lies left of the root"),
+        };
+        assert_eq!(
+            MerkleAvlTree::verify_proof_strict(&truncated, root_hash),
+            Err(Error::InvalidProof)
+        );
+
+        // A genuine absence proof ends at an empty child and is well-formed.
+        tree.delete(5).unwrap();
+        let root_hash = tree.root_hash().unwrap();
+        let absent = tree.generate_proof(5).unwrap();
+        assert!(matches!(absent.steps().1, ProofNode::Empty));
+        assert_eq!(
+            MerkleAvlTree::verify_proof_strict(&absent, root_hash),
+            Err(Error::NotFound)
+        );
+        assert_eq!(
+            MerkleAvlTree::verify_proof_strict(&absent.clone().compact(), root_hash),
+            Err(Error::NotFound)
+        );
+        assert_eq!(
+            MerkleAvlTree::verify_proof_strict(&ProofNode::Empty, RootHash(EMPTY_HASH)),
+            Err(Error::NotFound)
+        );
+    }
+
+    #[test]
+    fn test_range_root() {
+        let mut first = MerkleAvlTree::new();
+        let mut second = MerkleAvlTree::new();
+        for key in 1..=20 {
+            first.insert(key, format!("value{}", key)).unwrap();
+        }
+        for key in (8..=30).rev() {
+            second.insert(key, format!("value{}", key)).unwrap();
+        }
+        second.insert(15, "value15".to_string()).unwrap();
+
+        assert_ne!(first.root_hash(), second.root_hash());
+        assert_eq!(first.range_root(10, 18), second.range_root(10, 18));
+        assert_ne!(first.range_root(5, 18), second.range_root(5, 18));
+
+        second.insert(12, "changed".to_string()).unwrap();
+        assert_ne!(first.range_root(10, 18), second.range_root(10, 18));
+    }
+
+    #[test]
+    fn test_max_value_len() {
+        let mut tree = MerkleAvlTree::with_max_value_len(8);
+        tree.insert(1, "short".to_string()).unwrap();
+        let before = tree.clone();
+
+        assert_eq!(
+            tree.insert(2, "far too long".to_string()),
+            Err(Error::ValueTooLarge)
+        );
+        assert_eq!(
+            tree.insert(1, "far too long".to_string()),
+            Err(Error::ValueTooLarge)
+        );
+        assert_eq!(tree, before);
+        assert_eq!(tree.lookup(2), Err(Error::NotFound));
+        assert!(tree.insert(2, "12345678".to_string()).is_ok());
+    }
+
+    #[test]
+    fn test_lazy_proof_iter() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 1..=10 {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+        let root_hash = tree.root_hash().unwrap();
+
+        let before = PROOFS_GENERATED.with(|count| count.get());
+        let mut iter = tree.lazy_proof_iter();
+        assert_eq!(PROOFS_GENERATED.with(|count| count.get()), before);
+
+        let pulled: Vec<_> = iter.by_ref().take(2).collect();
+        assert_eq!(PROOFS_GENERATED.with(|count| count.get()), before + 2);
+        for (key, value, proof) in &pulled {
+            assert_eq!(MerkleAvlTree::verify_proof(proof, root_hash), Ok((key, value)));
+        }
+        assert_eq!(pulled[0].0, 1);
+        assert_eq!(pulled[1].0, 2);
+    }
+
+    #[test]
+    fn test_find_invariant_violation() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 1..=7 {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+        assert_eq!(tree.find_invariant_violation(), None);
+
+        // 4 / (2, 6) / (1, 3, 5, 7): give node 6 a bogus height.
+        tree.root.as_mut().unwrap().right.as_mut().unwrap().height = 5;
+        assert_eq!(
+            tree.find_invariant_violation(),
+            Some(Violation {
+                key: 6,
+                kind: ViolationKind::BadHeight {
+                    expected: 2,
+                    actual: 5,
+                },
+            })
+        );
+
+        let mut tree = MerkleAvlTree::new();
+        for key in 1..=3 {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+        tree.root.as_mut().unwrap().left.as_mut().unwrap().key = 9;
+        assert_eq!(
+            tree.find_invariant_violation().map(|violation| violation.kind),
+            Some(ViolationKind::OutOfOrder {
+                lower: None,
+                upper: Some(2),
+            })
+        );
+    }
+
+    #[test]
+    fn test_rotations_do_not_rehash_values() {
+        let large = "x".repeat(4 * 1024);
+        let mut tree = MerkleAvlTree::new();
+        let before = VALUE_HASHES.with(|count| count.get());
+        // Ascending keys rotate on almost every insert.
+        for key in 0..256 {
+            tree.insert(key, large.clone()).unwrap();
+        }
+        assert_eq!(VALUE_HASHES.with(|count| count.get()) - before, 256);
+        assert_eq!(tree.find_invariant_violation(), None);
+    }
+
+    #[test]
+    fn test_range_iter_rev() {
+        let mut tree = MerkleAvlTree::new();
+        for key in (0..50).map(|key| key * 2) {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+
+        let descending: Vec<Key> = tree.range_iter_rev(11, 30).map(|(key, _)| *key).collect();
+        assert_eq!(descending, vec![30, 28, 26, 24, 22, 20, 18, 16, 14, 12]);
+
+        let ascending: Vec<Key> = tree.range_iter(11, 30).map(|(key, _)| *key).collect();
+        let mut reversed = descending.clone();
+        reversed.reverse();
+        assert_eq!(ascending, reversed);
+
+        assert_eq!(tree.range_iter_rev(200, 300).count(), 0);
+        assert_eq!(tree.range_iter_rev(0, 0).count(), 1);
+    }
+
+    #[test]
+    fn test_verify_deletion() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 1..=10 {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+        let value = "value6".to_string();
+        let old_root = tree.root_hash().unwrap();
+        let membership = tree.generate_proof(6).unwrap();
+
+        tree.delete(6).unwrap();
+        let new_root = tree.root_hash().unwrap();
+        let absence = tree.generate_proof(6).unwrap();
+
+        assert_eq!(
+            MerkleAvlTree::verify_deletion(6, &value, old_root, &membership, new_root, &absence),
+            Ok(())
+        );
+        assert_eq!(
+            MerkleAvlTree::verify_deletion(6, &value, old_root, &membership, old_root, &absence),
+            Err(Error::InvalidProof)
+        );
+        let other = tree.generate_proof(7).unwrap();
+        assert_eq!(
+            MerkleAvlTree::verify_deletion(6, &value, old_root, &membership, new_root, &other),
+            Err(Error::InvalidProof)
+        );
+    }
+
+    #[test]
+    fn test_root_hex_and_display() {
+        let mut tree = MerkleAvlTree::new();
+        assert_eq!(tree.root_hex(), None);
+        assert_eq!(tree.to_string(), "MerkleAvlTree { len: 0, root: empty }");
+
+        tree.insert(1, "value1".to_string()).unwrap();
+        tree.insert(2, "value2".to_string()).unwrap();
+        tree.insert(2, "value2b".to_string()).unwrap();
+        let hex = tree.root_hex().unwrap();
+        assert_eq!(hex.len(), 2 * HASH_BYTES);
+        let root = tree.root_hash().unwrap();
+        assert_eq!(hex, format!("{:0width$x}", root, width = 2 * HASH_BYTES));
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.to_string(), format!("MerkleAvlTree {{ len: 2, root: 0x{} }}", hex));
+
+        tree.delete(1).unwrap();
+        assert_eq!(tree.len(), 1);
+    }
+
+    #[test]
+    fn test_lookup_many() {
+        let mut tree = MerkleAvlTree::new();
+        for key in [10, 20, 30] {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+        let (v10, v30): (Cow<Value>, Cow<Value>) =
+            (Cow::Owned("value10".to_string()), Cow::Owned("value30".to_string()));
+
+        assert_eq!(
+            tree.lookup_many(&[30, 15, 10, 30, 99]),
+            vec![Ok(v30.clone()), Err(Error::NotFound), Ok(v10), Ok(v30), Err(Error::NotFound)]
+        );
+        assert!(tree.lookup_many(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_child_positions_are_distinct() {
+        let child = Node::compute_hash(&1, Node::value_hash(NO_SALT, &"one".to_string()), None, None);
+        let value_hash = Node::value_hash(NO_SALT, &"two".to_string());
+        let left_only = Node::compute_hash(&2, value_hash, Some(child), None);
+        let right_only = Node::compute_hash(&2, value_hash, None, Some(child));
+        assert_ne!(left_only, right_only);
+        assert_eq!(ProofNode::Empty.hash(NO_SALT), EMPTY_HASH);
+
+        let mut tree = MerkleAvlTree::new();
+        tree.insert(2, "two".to_string()).unwrap();
+        tree.insert(1, "one".to_string()).unwrap();
+        assert_eq!(tree.root_hash(), Some(RootHash::from(left_only)));
+    }
+
+    #[test]
+    fn test_from_sorted_iter() {
+        let pairs: Vec<(Key, Value)> = (0..10_000).map(|key| (key, format!("value{}", key))).collect();
+        let streamed = MerkleAvlTree::from_sorted_iter(pairs.clone().into_iter(), pairs.len());
+        let built = MerkleAvlTree::from_sorted(pairs);
+
+        assert_eq!(streamed.root_hash(), built.root_hash());
+        assert_eq!(streamed.len(), 10_000);
+        assert_eq!(streamed.find_invariant_violation(), None);
+        assert_eq!(*streamed.lookup(4_321).unwrap(), "value4321");
+    }
+
+    #[test]
+    fn test_debug_verify_pinpoints_tampered_level() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 1..=15 {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+        let proof = tree.generate_proof(5).unwrap();
+        let root = tree.root_hash().unwrap();
+        assert_eq!(MerkleAvlTree::debug_verify(&proof, root), Ok(()));
+
+        // 8 / 4 / 6 / 5: corrupt the sibling hash recorded at level 1.
+        let tampered = match proof {
+            ProofNode::Left(key, value_hash, child_proof, right) => match *child_proof {
+                ProofNode::Right(child_key, child_value_hash, _, grandchild) => ProofNode::Left(
+                    key,
+                    value_hash,
+                    Box::new(ProofNode::Right(
+                        child_key,
+                        child_value_hash,
+                        Some(fake_hash(42)),
+                        grandchild,
+                    )),
+                    right,
+                ),
+                _ => panic!("5 lies right of 4"),
+            },
+            _ => panic!("5 lies left of 8"),
+        };
+        let trace = MerkleAvlTree::debug_verify(&tampered, root).unwrap_err();
+        assert_eq!(trace.root_hash, root);
+        assert_eq!(trace.levels.len(), 4);
+        assert_eq!(trace.divergence(&tree), Some(1));
+        assert_eq!(trace.levels[1].key, Some(4));
+        assert_ne!(RootHash::from(trace.levels[0].computed), root);
+        assert_eq!(trace.levels[1].went_left, Some(false));
+        assert_eq!(trace.levels[3].went_left, None);
+    }
+
+    #[test]
+    fn test_savepoint_rollback() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 1..=5 {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+        let root_hash = tree.root_hash();
+        let savepoint = tree.savepoint();
+
+        tree.insert(6, "value6".to_string()).unwrap();
+        tree.insert(2, "changed".to_string()).unwrap();
+        tree.delete(4).unwrap();
+        assert_ne!(tree.root_hash(), root_hash);
+
+        tree.rollback(savepoint);
+        assert_eq!(tree.root_hash(), root_hash);
+        assert_eq!(tree.len(), 5);
+        let entries: Vec<(Key, Value)> = tree.iter().map(|(k, v)| (*k, v.into_owned())).collect();
+        let expected: Vec<(Key, Value)> = (1..=5).map(|key| (key, format!("value{}", key))).collect();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn test_deepest_path() {
+        let mut tree = MerkleAvlTree::new();
+        assert!(tree.deepest_path().is_empty());
+        // Ascending inserts would build a chain without rebalancing.
+        for key in 1..=20 {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+
+        let path = tree.deepest_path();
+        assert_eq!(path.len() as i32, tree.height());
+        assert_eq!(Some(path[0]), tree.root.as_ref().map(|n| n.key));
+        for pair in path.windows(2) {
+            assert_eq!(tree.lca(pair[0], pair[1]).unwrap().0, &pair[0]);
+        }
+    }
+
+    #[test]
+    fn test_verify_membership() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 1..=7 {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+        let root_hash = tree.root_hash().unwrap();
+        let proof = tree.generate_proof(7).unwrap();
+
+        assert_eq!(
+            MerkleAvlTree::verify_membership(&proof, root_hash, 7),
+            Ok("value7".to_string())
+        );
+        assert_eq!(
+            MerkleAvlTree::verify_membership(&proof, root_hash, 10),
+            Err(Error::KeyMismatch)
+        );
+        assert_eq!(
+            MerkleAvlTree::verify_membership(&proof, RootHash::from(fake_hash(1)), 7),
+            Err(Error::InvalidProof)
+        );
+    }
+
+    #[test]
+    fn test_verify_membership_with_salt() {
+        let mut tree = MerkleAvlTree::with_salt(b"pepper");
+        for key in 1..=7 {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+        let root_hash = tree.root_hash().unwrap();
+        let proof = tree.generate_proof(7).unwrap();
+        let verify = |key, salt: &[u8]| {
+            MerkleAvlTree::verify_membership_with_salt(&proof, root_hash, key, salt)
+        };
+        assert_eq!(verify(7, b"pepper"), Ok("value7".to_string()));
+        assert_eq!(verify(10, b"pepper"), Err(Error::KeyMismatch));
+        assert_eq!(verify(7, b"salt"), Err(Error::InvalidProof));
+        let unsalted = MerkleAvlTree::verify_membership(&proof, root_hash, 7);
+        assert_eq!(unsalted, Err(Error::InvalidProof));
+    }
+
+    #[test]
+    fn test_rebuild_after_deletes() {
+        let mut tree = MerkleAvlTree::with_rebuild_threshold(0.25);
+        for key in 1..=40 {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+        for key in (1..=40).filter(|key| key % 3 == 0) {
+            tree.delete(key).unwrap();
+        }
+
+        assert!(tree.rebuild_count() > 0);
+        let remaining: Vec<(Key, Value)> = (1..=40)
+            .filter(|key| key % 3 != 0)
+            .map(|key| (key, format!("value{}", key)))
+            .collect();
+        let entries: Vec<(Key, Value)> = tree.iter().map(|(k, v)| (*k, v.into_owned())).collect();
+        assert_eq!(entries, remaining);
+        assert_eq!(tree.len(), remaining.len());
+        assert_eq!(tree.find_invariant_violation(), None);
+
+        let mut plain = MerkleAvlTree::new();
+        plain.insert(1, "value1".to_string()).unwrap();
+        plain.delete(1).unwrap();
+        assert_eq!(plain.rebuild_count(), 0);
+    }
+
+    #[test]
+    fn test_mpt_nodes_round_trip() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 1..=15 {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+        let root_hash = tree.root_hash().unwrap();
+
+        let proof = tree.generate_proof(11).unwrap();
+        let nodes = MerkleAvlTree::proof_to_mpt_nodes(&proof);
+        assert_eq!(nodes.len(), 4);
+        let decoded = MerkleAvlTree::proof_from_mpt_nodes(&nodes).unwrap();
+        assert_eq!(decoded, proof);
+        assert_eq!(
+            MerkleAvlTree::verify_proof(&decoded, root_hash),
+            Ok((&11, &"value11".to_string()))
+        );
+
+        let absence = tree.generate_proof(100).unwrap().compact();
+        let nodes = MerkleAvlTree::proof_to_mpt_nodes(&absence);
+        assert_eq!(MerkleAvlTree::proof_from_mpt_nodes(&nodes), Ok(absence));
+
+        let mut truncated = MerkleAvlTree::proof_to_mpt_nodes(&proof);
+        truncated.pop();
+        assert_eq!(
+            MerkleAvlTree::proof_from_mpt_nodes(&truncated),
+            Err(Error::MalformedProof)
+        );
+    }
+
+    #[test]
+    fn test_select_range() {
+        let mut tree = MerkleAvlTree::new();
+        for key in (1..=10).map(|key| key * 10) {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+
+        let page: Vec<Key> = tree.select_range(3, 6).into_iter().map(|(k, _)| *k).collect();
+        assert_eq!(page, vec![40, 50, 60]);
+        let tail: Vec<Key> = tree.select_range(8, 20).into_iter().map(|(k, _)| *k).collect();
+        assert_eq!(tail, vec![90, 100]);
+        assert!(tree.select_range(10, 12).is_empty());
+        assert!(tree.select_range(5, 2).is_empty());
+    }
+
+    #[test]
+    fn test_self_check_proof() {
+        let mut tree = MerkleAvlTree::with_salt(b"server");
+        assert_eq!(tree.self_check_proof(1), Err(Error::Empty));
+        for key in (1..=20).map(|key| key * 2) {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+        for key in 0..=42 {
+            assert_eq!(tree.self_check_proof(key), Ok(()));
+        }
+
+        // A value edited behind the hashes no longer matches its proof.
+        tree.root.as_mut().unwrap().left.as_mut().unwrap().value = "stale".to_string();
+        let stale_key = tree.root.as_ref().unwrap().left.as_ref().unwrap().key;
+        assert_eq!(tree.self_check_proof(stale_key), Err(Error::InvalidProof));
+    }
+
+    #[test]
+    fn test_btreemap_round_trip() {
+        let map: BTreeMap<Key, Value> = (1..=50).map(|key| (key, format!("value{}", key))).collect();
+        let tree = MerkleAvlTree::from(map.clone());
+        assert_eq!(tree.len(), 50);
+        assert_eq!(*tree.lookup(17).unwrap(), "value17");
+        assert_eq!(BTreeMap::from(tree), map);
+
+        let mut tree = MerkleAvlTree::new();
+        for key in [5, 3, 9, 1] {
+            tree.insert(key, format!("value{}", key)).unwrap();
+        }
+        let root_hash = tree.root_hash();
+        let map = BTreeMap::from(tree.clone());
+        assert_eq!(map.keys().copied().collect::<Vec<_>>(), vec![1, 3, 5, 9]);
+        let rebuilt = MerkleAvlTree::from(map);
+        assert_eq!(rebuilt.iter().collect::<Vec<_>>(), tree.iter().collect::<Vec<_>>());
+        assert_eq!(rebuilt.root_hash(), root_hash);
+    }
+
+    #[test]
+    fn test_rotations_for_insert_matches_insert_with_stats() {
+        let mut tree = MerkleAvlTree::new();
+        let mut seen = 0;
+        // Ascending runs, descending runs and zig-zags cover single and
+        // double rotations on both sides.
+        let keys = (0..40).chain((100..140).rev()).chain([60, 50, 55, 52, 57, 51, 59]);
+        for key in keys.chain(0..5) {
+            let predicted = tree.rotations_for_insert(key);
+            let stats = tree.insert_with_stats(key, key.to_string()).unwrap();
+            assert_eq!(predicted, stats.rotations, "key {}", key);
+            seen |= 1 << predicted;
+        }
+        assert_eq!(0b111, seen, "expected inserts with 0, 1 and 2 rotations");
+    }
+
+    #[test]
+    fn test_tagged_proof_from_another_tree_is_wrong_tree() {
+        let mut tree_a = MerkleAvlTree::with_tree_id(1);
+        let mut tree_b = MerkleAvlTree::with_tree_id(2);
+        for key in 0..8 {
+            tree_a.insert(key, format!("a{}", key)).unwrap();
+            tree_b.insert(key, format!("b{}", key)).unwrap();
+        }
+        let proof = tree_a.generate_tagged_proof(3).unwrap();
+        let root_a = tree_a.root_hash().unwrap();
+        let root_b = tree_b.root_hash().unwrap();
+
+        assert_eq!(
+            MerkleAvlTree::verify_tagged_proof(&proof, root_b, tree_b.tree_id()),
+            Err(Error::WrongTree)
+        );
+        assert_eq!(
+            MerkleAvlTree::verify_tagged_proof(&proof, root_a, tree_a.tree_id()),
+            Ok((&3, &"a3".to_string()))
+        );
+
+        // Same id but tampered contents is still an ordinary invalid proof.
+        let mut tampered = proof.clone();
+        tampered.proof = tampered.proof.with_value(&"forged".to_string());
+        assert_eq!(
+            MerkleAvlTree::verify_tagged_proof(&tampered, root_a, tree_a.tree_id()),
+            Err(Error::InvalidProof)
+        );
+    }
+
+    #[test]
+    fn test_key_diff() {
+        let mut ours = MerkleAvlTree::new();
+        let mut theirs = MerkleAvlTree::new();
+        for key in 0..64 {
+            ours.insert(key, key.to_string()).unwrap();
+            theirs.insert(key, key.to_string()).unwrap();
+        }
+        ours.insert(100, "ours".to_string()).unwrap();
+        ours.delete(7).unwrap();
+        ours.delete(40).unwrap();
+        theirs.insert(-5, "theirs".to_string()).unwrap();
+        // A changed value is not a membership difference.
+        theirs.insert(20, "changed".to_string()).unwrap();
+
+        assert_eq!(theirs.key_diff(&ours), (vec![-5, 7, 40], vec![100]));
+        assert_eq!(ours.key_diff(&theirs), (vec![100], vec![-5, 7, 40]));
+        assert_eq!(ours.key_diff(&ours.clone()), (vec![], vec![]));
+        let everything: Vec<Key> = theirs.iter().map(|(key, _)| *key).collect();
+        assert_eq!(MerkleAvlTree::new().key_diff(&theirs), (vec![], everything));
+    }
+
+    #[test]
+    fn test_equal_keys_replace() {
+        let mut tree = MerkleAvlTree::new();
+        for key in [3, 1, 2] {
+            tree.insert(key, "first".to_string()).unwrap();
+        }
+        tree.insert(2, "second".to_string()).unwrap();
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree.lookup(2).as_deref(), Ok(&"second".to_string()));
+        let mut fresh = MerkleAvlTree::new();
+        for (key, value) in [(3, "first"), (1, "first"), (2, "second")] {
+            fresh.insert(key, value.to_string()).unwrap();
+        }
+        assert_eq!(tree.root_hash(), fresh.root_hash());
+    }
+
+    #[test]
+    fn test_insert_prehashed_matches_insert() {
+        let value = "x".repeat(1024);
+        let mut hashed = MerkleAvlTree::with_salt(b"pepper");
+        let mut prehashed = MerkleAvlTree::with_salt(b"pepper");
+        let value_hash = prehashed.hash_value(&value);
+        let before = VALUE_HASHES.with(|count| count.get());
+        for key in 0..32 {
+            prehashed.insert_prehashed(key, value.clone(), value_hash).unwrap();
+        }
+        assert_eq!(VALUE_HASHES.with(|count| count.get()) - before, 0);
+        for key in 0..32 {
+            hashed.insert(key, value.clone()).unwrap();
+        }
+        assert_eq!(prehashed.root_hash(), hashed.root_hash());
+        assert_eq!(prehashed, hashed);
+    }
+
+    #[test]
+    fn test_median() {
+        let mut tree = MerkleAvlTree::new();
+        assert_eq!(tree.median(), None);
+        for key in [50, 10, 40, 20, 30] {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        assert_eq!(tree.median(), Some((&30, Cow::Owned("30".to_string()))));
+        tree.insert(60, "60".to_string()).unwrap();
+        assert_eq!(tree.median(), Some((&30, Cow::Owned("30".to_string()))));
+        tree.delete(10).unwrap();
+        assert_eq!(tree.median(), Some((&40, Cow::Owned("40".to_string()))));
+    }
+
+    #[test]
+    fn test_proof_bytes_version() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..10 {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        for key in [4, 11] {
+            let proof = tree.generate_proof(key).unwrap().compact();
+            let mut bytes = proof.to_bytes();
+            assert_eq!(bytes[0], PROOF_VERSION);
+            assert_eq!(ProofNode::from_bytes(&bytes), Ok(proof));
+            bytes[0] = PROOF_VERSION + 1;
+            assert_eq!(ProofNode::from_bytes(&bytes), Err(Error::UnsupportedProofVersion));
+            bytes[0] = PROOF_VERSION;
+            bytes.pop();
+            assert_eq!(ProofNode::from_bytes(&bytes), Err(Error::MalformedProof));
+        }
+        assert_eq!(ProofNode::from_bytes(&[]), Err(Error::MalformedProof));
+        assert_eq!(ProofNode::from_bytes(&[PROOF_VERSION]), Err(Error::MalformedProof));
+    }
+
+    #[test]
+    fn test_leaf_hash() {
+        let mut tree = MerkleAvlTree::new();
+        tree.insert(9, "nine".to_string()).unwrap();
+        let leaf_hash = MerkleAvlTree::leaf_hash(&9, &"nine".to_string());
+        assert_eq!(tree.root_hash(), Some(RootHash::from(leaf_hash)));
+        assert_ne!(MerkleAvlTree::leaf_hash(&9, &"ten".to_string()), leaf_hash);
+        assert_ne!(MerkleAvlTree::leaf_hash(&10, &"nine".to_string()), leaf_hash);
+    }
+
+    #[test]
+    fn test_consistency_proof() {
+        let mut tree = MerkleAvlTree::with_salt(b"log");
+        let mut roots = vec![tree.root_hash()];
+        for key in 0..40 {
+            tree.insert(key * 3, format!("entry {}", key)).unwrap();
+            roots.push(tree.root_hash());
+        }
+        let new_root = tree.root_hash();
+        for old_len in [0, 1, 7, 16, 39, 40] {
+            let proof = tree.generate_consistency_proof(old_len).unwrap();
+            assert!(MerkleAvlTree::verify_consistency_proof(roots[old_len], new_root, &proof));
+            if old_len < 40 {
+                let later = roots[old_len + 1];
+                assert!(!MerkleAvlTree::verify_consistency_proof(later, new_root, &proof));
+            }
+        }
+        assert_eq!(tree.generate_consistency_proof(41), Err(Error::NotFound));
+
+        // Overwriting an old entry is not an append.
+        let mut proof = tree.generate_consistency_proof(10).unwrap();
+        let (key, _) = proof.old[3];
+        proof.appended.push((key, EMPTY_HASH));
+        assert!(!MerkleAvlTree::verify_consistency_proof(roots[10], new_root, &proof));
+
+        // A tree built out of order can't prove consistency.
+        let mut shuffled = MerkleAvlTree::new();
+        for key in [5, 1, 9, 3, 7, 0, 2] {
+            shuffled.insert(key, key.to_string()).unwrap();
+        }
+        assert_eq!(shuffled.generate_consistency_proof(3), Err(Error::InvalidProof));
+    }
+
+    #[test]
+    fn test_query() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..30 {
+            tree.insert(key, "x".repeat(key as usize % 5)).unwrap();
+        }
+        let found: Vec<Key> = tree
+            .query(8, 21, |value| value.len() >= 3)
+            .map(|(key, _)| *key)
+            .collect();
+        assert_eq!(found, vec![8, 9, 13, 14, 18, 19]);
+        assert_eq!(tree.query(21, 8, |_| true).count(), 0);
+    }
+
+    #[test]
+    fn test_clone_range() {
+        let mut tree = MerkleAvlTree::with_salt(b"shard");
+        for key in 0..500 {
+            tree.insert(key, format!("v{}", key)).unwrap();
+        }
+        let shard = tree.clone_range(120, 379);
+        assert_eq!(shard.len(), 260);
+        assert_eq!(shard.find_invariant_violation(), None);
+        assert!(shard.iter().eq(tree.range_iter(120, 379)));
+        assert_eq!(shard.range_root(120, 379), tree.range_root(120, 379));
+        assert_eq!(shard.range_root(120, 379), shard.range_root(Key::MIN, Key::MAX));
+        assert!(tree.clone_range(600, 700).is_empty());
+    }
+
+    #[test]
+    fn test_probe_sees_search_path() {
+        let visited = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let sink = visited.clone();
+        let mut tree = MerkleAvlTree::with_probe(move |view| sink.borrow_mut().push(view));
+        for key in 0..15 {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        visited.borrow_mut().clear();
+
+        tree.lookup(9).unwrap();
+        let path: Vec<(Key, usize)> = visited.borrow().iter().map(|v| (v.key, v.depth)).collect();
+        assert_eq!(path, vec![(7, 0), (11, 1), (9, 2)]);
+
+        visited.borrow_mut().clear();
+        assert_eq!(tree.delete(20), Err(Error::NotFound));
+        let keys: Vec<Key> = visited.borrow().iter().map(|v| v.key).collect();
+        assert_eq!(keys, vec![7, 11, 13, 14]);
+
+        // The probe doesn't take part in equality.
+        let mut plain = MerkleAvlTree::new();
+        for key in 0..15 {
+            plain.insert(key, key.to_string()).unwrap();
+        }
+        assert_eq!(tree, plain);
+    }
+
+    #[test]
+    fn test_proof_cache() {
+        let mut tree = MerkleAvlTree::with_proof_cache(2);
+        for key in 0..10 {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        let generated = || PROOFS_GENERATED.with(|count| count.get());
+        let before = generated();
+        let first = tree.generate_proof(4).unwrap();
+        assert_eq!(tree.generate_proof(4).unwrap(), first);
+        assert_eq!(tree.proof_cache_stats(), ProofCacheStats { hits: 1, misses: 1 });
+        assert_eq!(generated() - before, 1);
+
+        // Filling past capacity evicts the least recently used proof.
+        tree.generate_proof(5).unwrap();
+        tree.generate_proof(6).unwrap();
+        tree.generate_proof(4).unwrap();
+        assert_eq!(tree.proof_cache_stats(), ProofCacheStats { hits: 1, misses: 4 });
+
+        // A new root invalidates the cache.
+        tree.generate_proof(6).unwrap();
+        tree.insert(4, "changed".to_string()).unwrap();
+        let after = tree.generate_proof(6).unwrap();
+        assert_eq!(tree.proof_cache_stats(), ProofCacheStats { hits: 2, misses: 5 });
+        assert!(MerkleAvlTree::verify_proof(&after, tree.root_hash().unwrap()).is_ok());
+
+        assert_eq!(MerkleAvlTree::new().proof_cache_stats(), ProofCacheStats::default());
+    }
+
+    #[test]
+    fn test_verify_hashes() {
+        let mut tree = MerkleAvlTree::with_salt(b"check");
+        assert!(tree.verify_hashes());
+        for key in 0..100 {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        assert!(tree.verify_hashes());
+
+        // A value swapped behind the tree's back no longer matches its hash.
+        let mut tampered = tree.clone();
+        tampered.root.as_mut().unwrap().right.as_mut().unwrap().value = "x".to_string();
+        assert!(!tampered.verify_hashes());
+        // So does a node hash that skipped its update.
+        let mut stale = tree.clone();
+        stale.root.as_mut().unwrap().left.as_mut().unwrap().hash = fake_hash(1);
+        assert!(!stale.verify_hashes());
+
+        #[cfg(feature = "parallel")]
+        for tree in [&tree, &tampered, &stale, &MerkleAvlTree::new()] {
+            assert_eq!(tree.verify_hashes_parallel(), tree.verify_hashes());
+        }
+    }
+
+    #[test]
+    fn test_value_index() {
+        let first_word = |value: &Value| value.split(' ').next().unwrap_or_default().to_string();
+        let mut tree = MerkleAvlTree::with_value_index(first_word);
+        for (key, value) in [(1, "red apple"), (2, "green pear"), (3, "red cherry"), (4, "red")] {
+            tree.insert(key, value.to_string()).unwrap();
+        }
+        let red = "red".to_string();
+        let green = "green".to_string();
+        assert_eq!(tree.keys_for(&red), &[1, 3, 4]);
+        assert_eq!(tree.keys_for(&green), &[2]);
 
-        assert!(!MerkleAvlTree::verify_proof(&proof, fake_root_hash));
+        tree.insert(3, "green grape".to_string()).unwrap();
+        assert_eq!(tree.keys_for(&red), &[1, 4]);
+        assert_eq!(tree.keys_for(&green), &[2, 3]);
+
+        tree.delete(2).unwrap();
+        tree.delete(3).unwrap();
+        assert!(tree.keys_for(&green).is_empty());
+        assert!(MerkleAvlTree::new().keys_for(&red).is_empty());
+    }
+
+    #[test]
+    fn test_balance_score() {
+        assert_eq!(MerkleAvlTree::new().balance_score(), 1.0);
+        let entries = (0..143).map(|key| (key, String::new())).collect();
+        let balanced = MerkleAvlTree::from_sorted(entries);
+        assert_eq!(balanced.balance_score(), 1.0);
+
+        // Inserting a Fibonacci tree level by level builds the tallest AVL
+        // shape for its size: height 10 for 143 nodes, against a minimum of 8.
+        let mut sizes: Vec<Key> = vec![0, 1];
+        for h in 2..=10 {
+            sizes.push(1 + sizes[h - 1] + sizes[h - 2]);
+        }
+        let mut skewed = MerkleAvlTree::new();
+        let mut queue = std::collections::VecDeque::from([(0, 10)]);
+        while let Some((lo, h)) = queue.pop_front() {
+            if h == 0 {
+                continue;
+            }
+            let key = lo + sizes[h - 1];
+            skewed.insert(key, String::new()).unwrap();
+            queue.push_back((lo, h - 1));
+            if h >= 2 {
+                queue.push_back((key + 1, h - 2));
+            }
+        }
+        assert_eq!(skewed.len(), 143);
+        assert_eq!(skewed.height(), 10);
+        assert_eq!(skewed.balance_score(), 1.25);
+    }
+
+    #[test]
+    fn test_subtree_proofs() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..31 {
+            tree.insert(key, format!("v{}", key)).unwrap();
+        }
+        let shard = tree.root.as_ref().unwrap().left.as_ref().unwrap().key;
+        let shard_hash = tree.subtree_hash(shard).unwrap();
+        let proofs = tree.subtree_proofs(shard).unwrap();
+        assert_eq!(proofs.len(), 15);
+        for (key, value, proof) in &proofs {
+            assert_eq!(MerkleAvlTree::verify_proof(proof, shard_hash), Ok((key, value)));
+            assert!(MerkleAvlTree::verify_proof(proof, tree.root_hash().unwrap()).is_err());
+        }
+        assert_eq!(tree.subtree_proofs(99), Err(Error::NotFound));
+        assert_eq!(tree.subtree_hash(99), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn test_commit_sorted() {
+        for len in [0, 1, 2, 3, 10, 64, 100] {
+            let pairs: Vec<(Key, Value)> = (0..len).map(|key| (key * 2, key.to_string())).collect();
+            let tree = MerkleAvlTree::from_sorted(pairs.clone());
+            assert_eq!(MerkleAvlTree::commit_sorted(&pairs), tree.root_hash());
+        }
+    }
+
+    #[test]
+    fn test_drain_filter() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 1..=10 {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        let drained = tree.drain_filter(|key, _| key % 2 == 0);
+        let expected: Vec<(Key, Value)> = [2, 4, 6, 8, 10].map(|key| (key, key.to_string())).into();
+        assert_eq!(drained, expected);
+        assert_eq!(tree.len(), 5);
+        assert!(tree.iter().all(|(key, _)| key % 2 == 1));
+        assert_eq!(tree.find_invariant_violation(), None);
+
+        let root = tree.root_hash();
+        assert!(tree.drain_filter(|_, value| value.is_empty()).is_empty());
+        assert_eq!(tree.root_hash(), root);
+    }
+
+    #[test]
+    fn test_next_root_matches_applying_op() {
+        let mut tree = MerkleAvlTree::new();
+        let mut ops = Vec::new();
+        for i in 0..200 {
+            let key = (i * 37) % 101;
+            ops.push(match i % 5 {
+                0..=2 => Operation::Insert(key, format!("v{}", i)),
+                3 => Operation::Delete(key),
+                _ => Operation::Lookup(key),
+            });
+        }
+        for op in ops.iter().chain([&Operation::Delete(1000)]) {
+            let before = tree.clone();
+            let predicted = tree.next_root(op);
+            assert_eq!(tree, before);
+            let applied = match op {
+                Operation::Insert(key, value) => tree.insert(*key, value.clone()),
+                Operation::Delete(key) => tree.delete(*key),
+                Operation::Lookup(_) => Ok(()),
+            };
+            assert_eq!(predicted, applied.map(|_| tree.root_hash()), "{:?}", op);
+        }
+        while let Some(&key) = tree.iter().map(|(key, _)| key).nth(tree.len() / 2) {
+            let predicted = tree.next_root(&Operation::Delete(key)).unwrap();
+            tree.delete(key).unwrap();
+            assert_eq!(predicted, tree.root_hash());
+        }
+        assert_eq!(tree.next_root(&Operation::Delete(0)), Err(Error::Empty));
+    }
+
+    #[test]
+    fn test_frozen_tree_reads() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..20 {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        let root = tree.root_hash();
+        let frozen = tree.clone().freeze();
+        assert_eq!(frozen.root_hash(), root);
+        assert_eq!(frozen.len(), 20);
+        assert_eq!(frozen.lookup(7).as_deref(), Ok(&"7".to_string()));
+        assert!(frozen.range(5, 9).eq(tree.range_iter(5, 9)));
+        assert!(frozen.iter().eq(tree.iter()));
+        let proof = frozen.generate_proof(7).unwrap();
+        assert_eq!(MerkleAvlTree::verify_proof(&proof, root.unwrap()), Ok((&7, &"7".to_string())));
+
+        let mut thawed = frozen.thaw();
+        assert_eq!(thawed, tree);
+        thawed.insert(20, "20".to_string()).unwrap();
+        assert_ne!(thawed.root_hash(), root);
+    }
+
+    #[test]
+    fn test_proof_size_estimate() {
+        let mut tree = MerkleAvlTree::new();
+        assert_eq!(tree.proof_size_estimate(1), 0);
+        for key in 0..50 {
+            tree.insert(key * 2, "v".repeat(key as usize)).unwrap();
+        }
+        for key in [0, 1, 17, 48, 49, 98, 200, -3] {
+            let bytes = tree.generate_proof(key).unwrap().to_bytes();
+            assert_eq!(tree.proof_size_estimate(key), bytes.len(), "key {}", key);
+        }
+    }
+
+    #[test]
+    fn test_shard() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..101 {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        let contents: Vec<(Key, Value)> = tree.clone().into_sorted_vec();
+        let shards = tree.shard(4);
+        let sizes: Vec<usize> = shards.iter().map(|shard| shard.len()).collect();
+        assert_eq!(sizes, vec![26, 25, 25, 25]);
+        for shard in &shards {
+            assert_eq!(shard.find_invariant_violation(), None);
+        }
+        let reassembled: Vec<(Key, Value)> =
+            shards.into_iter().flat_map(|shard| shard.into_sorted_vec()).collect();
+        assert_eq!(reassembled, contents);
+        assert_eq!(MerkleAvlTree::new().shard(3).len(), 3);
+    }
+
+    #[test]
+    fn test_proof_still_valid() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..10 {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        let proof = tree.generate_proof(4).unwrap();
+        let old_root = tree.root_hash().unwrap();
+        assert_eq!(MerkleAvlTree::proof_still_valid(&proof, &[], old_root), Some(true));
+
+        let untouched = [
+            Operation::Insert(20, "20".to_string()),
+            Operation::Delete(3),
+            Operation::Lookup(4),
+        ];
+        tree.insert(20, "20".to_string()).unwrap();
+        tree.delete(3).unwrap();
+        let new_root = tree.root_hash().unwrap();
+        assert_eq!(MerkleAvlTree::proof_still_valid(&proof, &untouched, new_root), Some(true));
+        assert_eq!(MerkleAvlTree::proof_still_valid(&proof, &[], new_root), None);
+
+        let overwritten = [Operation::Insert(4, "four".to_string())];
+        assert_eq!(MerkleAvlTree::proof_still_valid(&proof, &overwritten, new_root), Some(false));
+        let deleted = [Operation::Delete(4)];
+        assert_eq!(MerkleAvlTree::proof_still_valid(&proof, &deleted, new_root), Some(false));
+        let restored = [Operation::Delete(4), Operation::Insert(4, "4".to_string())];
+        assert_eq!(MerkleAvlTree::proof_still_valid(&proof, &restored, new_root), Some(true));
+
+        let absence = tree.generate_proof(50).unwrap();
+        assert_eq!(MerkleAvlTree::proof_still_valid(&absence, &untouched, new_root), None);
+    }
+
+    #[test]
+    fn test_second_min_and_max() {
+        let mut tree = MerkleAvlTree::new();
+        assert_eq!((tree.second_min(), tree.second_max()), (None, None));
+        tree.insert(10, "10".to_string()).unwrap();
+        assert_eq!((tree.second_min(), tree.second_max()), (None, None));
+        assert_eq!(tree.max(), tree.min());
+        tree.insert(20, "20".to_string()).unwrap();
+        assert_eq!(tree.second_min(), Some((&20, Cow::Owned("20".to_string()))));
+        assert_eq!(tree.second_max(), Some((&10, Cow::Owned("10".to_string()))));
+        for key in [5, 15, 25] {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        assert_eq!(tree.min(), Some((&5, Cow::Owned("5".to_string()))));
+        assert_eq!(tree.second_min(), Some((&10, Cow::Owned("10".to_string()))));
+        assert_eq!(tree.second_max(), Some((&20, Cow::Owned("20".to_string()))));
+        assert_eq!(tree.max(), Some((&25, Cow::Owned("25".to_string()))));
+    }
+
+    #[test]
+    fn test_error_into_box_dyn_error() {
+        fn lookup_len(tree: &MerkleAvlTree, key: Key) -> Result<usize, Box<dyn std::error::Error>> {
+            Ok(tree.lookup(key)?.len())
+        }
+        let mut tree = MerkleAvlTree::new();
+        let err = lookup_len(&tree, 1).unwrap_err();
+        assert_eq!(err.to_string(), "tree is empty");
+        tree.insert(1, "one".to_string()).unwrap();
+        assert_eq!(lookup_len(&tree, 1).unwrap(), 3);
+        let err = lookup_len(&tree, 2).unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::NotFound));
+    }
+
+    #[test]
+    fn test_completeness_proof() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..40 {
+            tree.insert(key * 3, key.to_string()).unwrap();
+        }
+        let root = tree.root_hash().unwrap();
+        for (lo, hi) in [(10, 40), (9, 42), (0, 0), (-10, 5), (100, 200), (13, 14), (50, 50)] {
+            let proof = tree.generate_completeness_proof(lo, hi).unwrap();
+            let found = MerkleAvlTree::verify_completeness_proof(&proof, root).unwrap();
+            let found = found.into_iter().map(|(key, value)| (key, Cow::Borrowed(value)));
+            assert!(found.eq(tree.range_iter(lo, hi)), "[{}, {}]", lo, hi);
+        }
+
+        let honest = tree.generate_completeness_proof(10, 40).unwrap();
+        let mut dropped = honest.clone();
+        dropped.entries.remove(4);
+        assert_eq!(
+            MerkleAvlTree::verify_completeness_proof(&dropped, root),
+            Err(Error::InvalidProof)
+        );
+        let mut truncated = honest.clone();
+        truncated.entries.pop();
+        assert!(MerkleAvlTree::verify_completeness_proof(&truncated, root).is_err());
+        let mut shifted = honest;
+        shifted.hi = 50;
+        assert!(MerkleAvlTree::verify_completeness_proof(&shifted, root).is_err());
+    }
+
+    #[test]
+    fn test_page() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..25 {
+            tree.insert(key * 2, key.to_string()).unwrap();
+        }
+        let mut seen = Vec::new();
+        let mut sizes = Vec::new();
+        let mut after = None;
+        loop {
+            let (page, next) = tree.page(after, 10);
+            sizes.push(page.len());
+            seen.extend(page.into_iter().map(|(key, _)| key));
+            match next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(sizes, vec![10, 10, 5]);
+        assert_eq!(seen, (0..25).map(|key| key * 2).collect::<Vec<_>>());
+
+        assert_eq!(tree.page(Some(47), 10), (vec![(48, "24".to_string())], None));
+        // A final page that is exactly full still ends the listing.
+        let (page, next) = tree.page(Some(38), 5);
+        assert_eq!((page.len(), next), (5, None));
+        assert_eq!(tree.page(Some(Key::MAX), 10), (vec![], None));
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..500 {
+            tree.insert(key * 2, String::new()).unwrap();
+        }
+        // About 10 bits and 7 hashes per key gives roughly a 1% false
+        // positive rate.
+        let filter = tree.bloom_filter(5000, 7);
+        assert_eq!(filter.len(), 1 + 625);
+        assert!((0..500).all(|key| MerkleAvlTree::bloom_might_contain(&filter, key * 2)));
+        let false_positives = (0..10_000)
+            .filter(|key| MerkleAvlTree::bloom_might_contain(&filter, key * 2 + 1))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+
+        let empty = MerkleAvlTree::new().bloom_filter(64, 3);
+        assert!(!MerkleAvlTree::bloom_might_contain(&empty, 1));
+        assert!(!MerkleAvlTree::bloom_might_contain(&[], 1));
+    }
+
+    #[test]
+    fn test_map_values() {
+        let mut tree = MerkleAvlTree::new();
+        let mut expected = MerkleAvlTree::new();
+        for key in 0..30 {
+            tree.insert(key, format!("value {}", key)).unwrap();
+            expected.insert(key, format!("VALUE {}", key)).unwrap();
+        }
+        let before = tree.root_hash();
+        tree.map_values(|_, value| *value = value.to_uppercase());
+        assert_ne!(tree.root_hash(), before);
+        assert_eq!(tree.root_hash(), expected.root_hash());
+        assert!(tree.iter().eq(expected.iter()));
+        assert!(tree.verify_hashes());
+    }
+
+    #[test]
+    fn test_generate_proof_at_snapshot() {
+        let mut live = MerkleAvlTree::new();
+        for key in 0..20 {
+            live.insert(key, format!("old {}", key)).unwrap();
+        }
+        let snapshot = live.clone();
+        let old_root = snapshot.root_hash().unwrap();
+        for key in 0..20 {
+            live.insert(key, format!("new {}", key)).unwrap();
+        }
+        live.delete(5).unwrap();
+        live.insert(100, "later".to_string()).unwrap();
+
+        let old_proof = live.generate_proof_at(&snapshot, 5).unwrap();
+        assert_eq!(
+            MerkleAvlTree::verify_proof(&old_proof, old_root),
+            Ok((&5, &"old 5".to_string()))
+        );
+        assert_eq!(snapshot.root_hash(), Some(old_root));
+        assert!(snapshot.verify_hashes());
+        let new_root = live.root_hash().unwrap();
+        assert!(MerkleAvlTree::verify_proof(&old_proof, new_root).is_err());
+    }
+
+    #[test]
+    fn test_would_change() {
+        let mut tree = MerkleAvlTree::new();
+        assert!(tree.would_change(1, &"one".to_string()));
+        tree.insert(1, "one".to_string()).unwrap();
+        assert!(!tree.would_change(1, &"one".to_string()));
+        assert!(tree.would_change(1, &"uno".to_string()));
+        assert!(tree.would_change(2, &"one".to_string()));
+    }
+
+    #[test]
+    fn test_canonical_contents_bytes() {
+        let mut ascending = MerkleAvlTree::new();
+        let mut scattered = MerkleAvlTree::with_salt(b"other");
+        for key in 0..50 {
+            ascending.insert(key, key.to_string()).unwrap();
+            scattered.insert((key * 17) % 50, ((key * 17) % 50).to_string()).unwrap();
+        }
+        scattered.insert(60, "gone".to_string()).unwrap();
+        scattered.delete(60).unwrap();
+        assert_ne!(scattered.root_hash(), ascending.root_hash());
+        assert_eq!(scattered.canonical_contents_bytes(), ascending.canonical_contents_bytes());
+
+        let mut single = MerkleAvlTree::new();
+        single.insert(-2, "ab".to_string()).unwrap();
+        let expected = [&[0, 0, 0, 0, 0, 0, 0, 1][..], &[255, 255, 255, 254], &[0, 0, 0, 2], b"ab"];
+        assert_eq!(single.canonical_contents_bytes(), expected.concat());
+        assert_eq!(MerkleAvlTree::new().canonical_contents_bytes(), vec![0; 8]);
+    }
+
+    #[test]
+    fn test_delete_rank_range() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..10 {
+            tree.insert(key * 10, key.to_string()).unwrap();
+        }
+        let removed = tree.delete_rank_range(2, 5);
+        let expected: Vec<(Key, Value)> = [20, 30, 40].map(|key| (key, (key / 10).to_string())).into();
+        assert_eq!(removed, expected);
+        let survivors: Vec<Key> = tree.iter().map(|(key, _)| *key).collect();
+        assert_eq!(survivors, vec![0, 10, 50, 60, 70, 80, 90]);
+        assert_eq!(tree.len(), 7);
+
+        assert_eq!(tree.delete_rank_range(5, 100).len(), 2);
+        assert!(tree.delete_rank_range(4, 2).is_empty());
+        assert_eq!(tree.len(), 5);
+    }
+
+    #[test]
+    fn test_verify_proof_bounded() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..31 {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        let root = tree.root_hash().unwrap();
+        let height = tree.height() as usize;
+        let leaf = tree.root.as_ref().unwrap().left.as_ref().unwrap().left.as_ref().unwrap();
+        let (shallow, deep) = (leaf.key, tree.deepest_path()[height - 1]);
+        let proof = tree.generate_proof(deep).unwrap();
+        assert!(MerkleAvlTree::verify_proof_bounded(&proof, root, height).is_ok());
+        assert_eq!(
+            MerkleAvlTree::verify_proof_bounded(&proof, root, height - 1),
+            Err(Error::ProofTooDeep)
+        );
+        let proof = tree.generate_proof(shallow).unwrap();
+        assert!(MerkleAvlTree::verify_proof_bounded(&proof, root, 3).is_ok());
+
+        // Padding a proof with fake levels trips the bound before hashing.
+        let mut padded = proof;
+        for _ in 0..100 {
+            padded = ProofNode::Left(Key::MAX, EMPTY_HASH, Box::new(padded), None);
+        }
+        assert_eq!(
+            MerkleAvlTree::verify_proof_bounded(&padded, root, height),
+            Err(Error::ProofTooDeep)
+        );
+    }
+
+    #[test]
+    fn test_read_tx_holds_many_borrows() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..10 {
+            tree.insert(key, format!("v{}", key)).unwrap();
+        }
+        let tx = tree.read_tx();
+        let held: Vec<Cow<Value>> = [1, 4, 9].iter().filter_map(|&key| tx.get(key)).collect();
+        let other = tx.get(2).unwrap();
+        assert_eq!(held.iter().map(|v| v.as_str()).collect::<Vec<_>>(), vec!["v1", "v4", "v9"]);
+        assert_eq!(*other, "v2");
+        assert_eq!(tx.get(10), None);
+    }
+
+    #[test]
+    fn test_streaming_committer() {
+        for len in [0, 1, 2, 3, 4, 7, 8, 100, 10_000] {
+            let pairs: Vec<(Key, Value)> =
+                (0..len).map(|key| (key * 2, key.to_string())).collect();
+            let mut committer = StreamingCommitter::new(pairs.len());
+            for (key, value) in &pairs {
+                committer.push(*key, value);
+            }
+            let expected = MerkleAvlTree::from_sorted(pairs).root_hash();
+            assert_eq!(committer.finalize(), expected, "len {}", len);
+        }
+    }
+
+    #[test]
+    fn test_collision_check_refuses_colliding_insert() {
+        let mut tree = MerkleAvlTree::with_collision_check();
+        tree.insert(1, "one".to_string()).unwrap();
+        tree.insert(1, "uno".to_string()).unwrap();
+        tree.insert(2, "two".to_string()).unwrap();
+        tree.delete(2).unwrap();
+        assert_eq!(tree.leaf_hashes.as_ref().unwrap().len(), 1);
+
+        // A real 64-bit collision can't be found in a test, so plant an
+        // unrelated entry under the leaf hash the next insert will produce.
+        let value = "three".to_string();
+        let leaf_hash = Node::compute_hash(&3, tree.hash_value(&value), None, None);
+        let planted = (7, "seven".to_string());
+        tree.leaf_hashes.as_mut().unwrap().insert(leaf_hash, planted);
+        let before = tree.root_hash();
+        assert_eq!(tree.insert(3, value.clone()), Err(Error::HashCollision));
+        assert_eq!(tree.root_hash(), before);
+        assert_eq!(tree.lookup(3), Err(Error::NotFound));
+
+        let mut unchecked = MerkleAvlTree::new();
+        assert_eq!(unchecked.insert(3, value), Ok(()));
+    }
+
+    #[test]
+    fn test_remap_keys_shifts_every_key() {
+        let mut tree = MerkleAvlTree::new();
+        for key in [5, 1, 9, 3, 7] {
+            tree.insert(key, format!("v{}", key)).unwrap();
+        }
+        let remapped = tree.remap_keys(|key| key + 1000).unwrap();
+        let expected = MerkleAvlTree::from_sorted(
+            [1, 3, 5, 7, 9].iter().map(|key| (key + 1000, format!("v{}", key))).collect(),
+        );
+        assert_eq!(remapped.root_hash(), expected.root_hash());
+        assert_eq!(remapped.lookup(1005).as_deref(), Ok(&"v5".to_string()));
+        assert_eq!(remapped.lookup(5), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn test_remap_keys_rejects_collapsed_keys() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..4 {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        assert_eq!(
+            tree.remap_keys(|key| key / 2).map(|tree| tree.len()),
+            Err(Error::DuplicateKeyAfterRemap)
+        );
+    }
+
+    #[test]
+    fn test_gaps() {
+        let mut tree = MerkleAvlTree::new();
+        for key in [1, 2, 10, 11, 50] {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        assert_eq!(tree.gaps(5), vec![(2, 10), (11, 50)]);
+        assert_eq!(tree.gaps(8), vec![(11, 50)]);
+        tree.insert(Key::MIN, String::new()).unwrap();
+        tree.insert(Key::MAX, String::new()).unwrap();
+        assert_eq!(tree.gaps(100), vec![(Key::MIN, 1), (50, Key::MAX)]);
+    }
+
+    #[test]
+    fn test_mmr_peaks_round_trip() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..11 {
+            tree.insert(key * 3, format!("v{}", key)).unwrap();
+        }
+        // 11 = 8 + 2 + 1 leaves.
+        let peaks = tree.to_mmr_peaks();
+        assert_eq!(peaks.len(), 3);
+        for key in 0..11 {
+            let leaf = MerkleAvlTree::leaf_hash(&(key * 3), &format!("v{}", key));
+            let proof = tree.generate_mmr_proof(key * 3).unwrap();
+            assert!(MerkleAvlTree::verify_against_mmr(leaf, &proof, &peaks), "key {}", key);
+        }
+
+        let proof = tree.generate_mmr_proof(9).unwrap();
+        assert_eq!((proof.peak, proof.position, proof.siblings.len()), (0, 3, 3));
+        let wrong_leaf = MerkleAvlTree::leaf_hash(&9, &"v4".to_string());
+        assert!(!MerkleAvlTree::verify_against_mmr(wrong_leaf, &proof, &peaks));
+        let moved = MmrProof { position: 2, ..proof.clone() };
+        let leaf = MerkleAvlTree::leaf_hash(&9, &"v3".to_string());
+        assert!(!MerkleAvlTree::verify_against_mmr(leaf, &moved, &peaks));
+        assert_eq!(tree.generate_mmr_proof(1), Err(Error::NotFound));
+        assert!(MerkleAvlTree::new().to_mmr_peaks().is_empty());
+    }
+
+    /// Run-length encodes `value` as count-char pairs; fine for values
+    /// without digits.
+    fn run_length_encode(value: &Value) -> Value {
+        let mut encoded = String::new();
+        let mut chars = value.chars().peekable();
+        while let Some(c) = chars.next() {
+            let mut count = 1;
+            while chars.next_if_eq(&c).is_some() {
+                count += 1;
+            }
+            encoded.push_str(&format!("{}{}", count, c));
+        }
+        encoded
+    }
+
+    fn run_length_decode(encoded: &Value) -> Value {
+        let mut value = String::new();
+        let mut count = 0;
+        for c in encoded.chars() {
+            match c.to_digit(10) {
+                Some(digit) => count = count * 10 + digit as usize,
+                None => {
+                    value.extend(std::iter::repeat_n(c, count));
+                    count = 0;
+                }
+            }
+        }
+        value
+    }
+
+    #[test]
+    fn test_value_codec_commits_to_original() {
+        let large = format!("{}{}{}", "a".repeat(500), "b".repeat(300), "c".repeat(200));
+        let mut tree = MerkleAvlTree::with_value_codec(run_length_encode, run_length_decode);
+        let mut plain = MerkleAvlTree::new();
+        for (key, value) in [(1, large.clone()), (2, "xyz".to_string()), (3, "q".repeat(40))] {
+            tree.insert(key, value.clone()).unwrap();
+            plain.insert(key, value).unwrap();
+        }
+        assert_eq!(tree.lookup(1).as_deref(), Ok(&large));
+        assert_eq!(tree.lookup(2).as_deref(), Ok(&"xyz".to_string()));
+        assert_eq!(Node::find(&tree.root, 1).unwrap().value, "500a300b200c");
+        assert!(!tree.would_change(1, &large));
+        assert!(tree.iter().map(|(_, value)| value).eq(plain.iter().map(|(_, value)| value)));
+        assert_eq!(tree.root_hash(), plain.root_hash());
+
+        let proof = tree.generate_proof(1).unwrap();
+        let root = tree.root_hash().unwrap();
+        assert_eq!(MerkleAvlTree::verify_proof(&proof, root), Ok((&1, &large)));
+    }
+
+    #[test]
+    fn test_clone_range_keeps_codec() {
+        let mut tree = MerkleAvlTree::with_value_codec(run_length_encode, run_length_decode);
+        for key in 0..20 {
+            tree.insert(key, "z".repeat(key as usize + 1)).unwrap();
+        }
+        let shard = tree.clone_range(5, 14);
+        let value = "z".repeat(10);
+        assert_eq!(shard.lookup(9).as_deref(), Ok(&value));
+        assert!(shard.verify_hashes());
+        let proof = shard.generate_proof(9).unwrap();
+        let root = shard.root_hash().unwrap();
+        assert_eq!(MerkleAvlTree::verify_proof(&proof, root), Ok((&9, &value)));
+    }
+
+    #[test]
+    fn test_audit_report() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 1..=7 {
+            tree.insert(key * 10, key.to_string()).unwrap();
+        }
+        let report = tree.audit_report();
+        assert_eq!(report.len, 7);
+        assert_eq!(report.height, 3);
+        assert_eq!(report.balance_score, 1.0);
+        assert_eq!(report.root_hash, tree.root_hash());
+        assert_eq!((report.min_key, report.max_key), (Some(10), Some(70)));
+        assert_eq!(report.violation, None);
+        let rendered = report.to_string();
+        assert!(rendered.contains("entries:       7\n"));
+        assert!(rendered.contains(&format!("0x{}", tree.root_hash().unwrap())));
+
+        let empty = MerkleAvlTree::new().audit_report();
+        assert_eq!((empty.len, empty.root_hash, empty.min_key), (0, None, None));
+        assert!(empty.to_string().contains("root hash:     empty"));
+    }
+
+    #[test]
+    fn test_insert_moves_value_without_copying() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..8 {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        let leaf = "x".repeat(64);
+        let leaf_buffer = leaf.as_ptr();
+        let overwrite = "y".repeat(64);
+        let overwrite_buffer = overwrite.as_ptr();
+        tree.insert(100, leaf).unwrap();
+        assert_eq!(tree.lookup(100).unwrap().as_ptr(), leaf_buffer);
+        tree.insert(3, overwrite).unwrap();
+        assert_eq!(tree.lookup(3).unwrap().as_ptr(), overwrite_buffer);
+    }
+
+    #[test]
+    fn test_divergence_point() {
+        let mut local = MerkleAvlTree::new();
+        for key in 0..63 {
+            local.insert(key, key.to_string()).unwrap();
+        }
+        let remote_of = |tree: &MerkleAvlTree| {
+            let tree = tree.clone();
+            move |key| tree.subtree_hash(key).ok().map(|root| root.0)
+        };
+        assert_eq!(local.divergence_point(remote_of(&local)), None);
+
+        for changed in [0, 17, 31, 62] {
+            let mut remote = local.clone();
+            remote.insert(changed, "changed".to_string()).unwrap();
+            assert_eq!(local.divergence_point(remote_of(&remote)), Some(changed));
+        }
+        assert_eq!(local.divergence_point(|_| None), Some(31));
+    }
+
+    /// Whether `skeleton` is a perfect tree of `height` levels, with every
+    /// node's recorded height matching.
+    fn is_perfect(skeleton: &Option<Box<Skeleton>>, height: i32) -> bool {
+        match skeleton {
+            None => height == 0,
+            Some(s) => {
+                s.height == height
+                    && is_perfect(&s.left, height - 1)
+                    && is_perfect(&s.right, height - 1)
+            }
+        }
+    }
+
+    #[test]
+    fn test_skeleton_of_from_sorted_tree() {
+        let tree = MerkleAvlTree::from_sorted((0..15).map(|key| (key, "v".repeat(100))).collect());
+        let skeleton = tree.skeleton().unwrap();
+        assert_eq!((skeleton.key, skeleton.height), (7, 4));
+        assert!(is_perfect(&Some(Box::new(skeleton)), 4));
+
+        let small = MerkleAvlTree::from_sorted(vec![(1, "a".into()), (2, "b".into())]);
+        let rendered = small.skeleton().unwrap().to_string();
+        assert_eq!(rendered, "2 (2)\n  1 (1)\n  -\n");
+        assert_eq!(MerkleAvlTree::new().skeleton(), None);
+    }
+
+    #[test]
+    fn test_patch_turns_one_tree_into_another() {
+        let mut a = MerkleAvlTree::new();
+        for key in 0..40 {
+            a.insert(key * 2, key.to_string()).unwrap();
+        }
+        // b applies its changes to a copy of a in key order, as the patch does.
+        let mut b = a.clone();
+        b.delete(4).unwrap();
+        b.insert(11, "new".to_string()).unwrap();
+        b.insert(30, "changed".to_string()).unwrap();
+        b.delete(50).unwrap();
+        b.insert(100, "appended".to_string()).unwrap();
+
+        let patch = a.make_patch(&b);
+        assert_eq!(
+            patch.ops,
+            vec![
+                PatchOp::Delete(4),
+                PatchOp::Insert(11, "new".to_string()),
+                PatchOp::Update(30, "changed".to_string()),
+                PatchOp::Delete(50),
+                PatchOp::Insert(100, "appended".to_string()),
+            ]
+        );
+        let mut patched = a.clone();
+        patched.apply_patch(&patch).unwrap();
+        assert_eq!(patched.root_hash(), b.root_hash());
+        assert!(b.iter().eq(patched.iter()));
+        assert_eq!(b.make_patch(&patched), Patch::default());
+
+        let before = a.root_hash();
+        let stale = Patch { ops: vec![PatchOp::Delete(0), PatchOp::Update(1, "x".into())] };
+        assert_eq!(a.apply_patch(&stale), Err(Error::PatchConflict));
+        assert_eq!(a.root_hash(), before);
+    }
+
+    #[test]
+    fn test_try_from_sorted_rejects_unsorted_input() {
+        let pairs: Vec<(Key, Value)> = [1, 3, 2, 4].iter().map(|&k| (k, k.to_string())).collect();
+        assert_eq!(
+            MerkleAvlTree::try_from_sorted(pairs).map(|tree| tree.len()),
+            Err(Error::NotSorted { at_index: 2 })
+        );
+        let sorted: Vec<(Key, Value)> = (0..9).map(|k| (k, k.to_string())).collect();
+        let tree = MerkleAvlTree::try_from_sorted(sorted.clone()).unwrap();
+        assert_eq!(tree.root_hash(), MerkleAvlTree::from_sorted(sorted).root_hash());
+        assert!(MerkleAvlTree::try_from_sorted(Vec::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_try_from_sorted_rejects_duplicate_keys() {
+        let pairs: Vec<(Key, Value)> = [1, 2, 2, 3].iter().map(|&k| (k, k.to_string())).collect();
+        let err = MerkleAvlTree::try_from_sorted(pairs).map(|tree| tree.len()).unwrap_err();
+        assert_eq!(err, Error::DuplicateKey { key: 2 });
+        assert_eq!(err.to_string(), "key 2 appears more than once");
+    }
+
+    #[test]
+    fn test_proof_context_proves_a_sorted_range() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..200 {
+            tree.insert(key * 2, key.to_string()).unwrap();
+        }
+        let root = tree.root_hash().unwrap();
+        let mut context = tree.proof_context();
+        for key in 50..150 {
+            let proof = context.prove(key).unwrap();
+            assert_eq!(proof, tree.generate_proof(key).unwrap(), "key {}", key);
+            if key % 2 == 0 {
+                let value = (key / 2).to_string();
+                assert_eq!(MerkleAvlTree::verify_proof(&proof, root), Ok((&key, &value)));
+            }
+        }
+        // Neighbouring keys share most of their path.
+        assert!(context.reused > 100 * (tree.height() as usize - 3), "{}", context.reused);
+        assert_eq!(context.prove(-5), tree.generate_proof(-5));
+        assert_eq!(MerkleAvlTree::new().proof_context().prove(1), Err(Error::Empty));
+    }
+
+    #[test]
+    fn test_soft_delete_keeps_a_provable_tombstone() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..20 {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        let live_root = tree.root_hash();
+        tree.soft_delete(7).unwrap();
+        assert_ne!(tree.root_hash(), live_root);
+        assert_eq!(tree.lookup(7), Err(Error::Deleted));
+        assert_eq!(tree.soft_delete(7), Err(Error::Deleted));
+        assert_eq!(tree.soft_delete(20), Err(Error::NotFound));
+        assert_eq!(tree.len(), 19);
+        assert!(tree.verify_hashes());
+
+        let root = tree.root_hash().unwrap();
+        let proof = tree.generate_proof(7).unwrap();
+        assert_eq!(MerkleAvlTree::verify_tombstone(7, &proof, root, NO_SALT), Ok(()));
+        assert_eq!(MerkleAvlTree::verify_proof(&proof, root), Err(Error::Deleted));
+        assert_eq!(ProofNode::from_bytes(&proof.to_bytes()), Ok(proof.clone()));
+        assert_eq!(tree.proof_size_estimate(7), proof.to_bytes().len());
+        let live = tree.generate_proof(8).unwrap();
+        let verify = |key, proof| MerkleAvlTree::verify_tombstone(key, proof, root, NO_SALT);
+        assert_eq!(verify(8, &live), Err(Error::InvalidProof));
+        assert_eq!(verify(6, &proof), Err(Error::InvalidProof));
+
+        let mut salted = MerkleAvlTree::with_salt(b"pepper");
+        for key in 0..20 {
+            salted.insert(key, key.to_string()).unwrap();
+        }
+        salted.soft_delete(7).unwrap();
+        let root = salted.root_hash().unwrap();
+        let proof = salted.generate_proof(7).unwrap();
+        assert_eq!(MerkleAvlTree::verify_tombstone(7, &proof, root, b"pepper"), Ok(()));
+    }
+
+    #[test]
+    fn test_tombstones_read_as_absent() {
+        let mut tree = MerkleAvlTree::new();
+        let mut expected = MerkleAvlTree::new();
+        for key in 0..10 {
+            tree.insert(key, key.to_string()).unwrap();
+            if key % 3 != 0 {
+                expected.insert(key, key.to_string()).unwrap();
+            }
+        }
+        for key in [0, 3, 6, 9] {
+            tree.soft_delete(key).unwrap();
+        }
+        assert_eq!(tree.len(), 6);
+        assert!(tree.iter().eq(expected.iter()));
+        assert!(tree.range_iter_rev(2, 8).eq(expected.range_iter_rev(2, 8)));
+        assert_eq!(tree.min(), expected.min());
+        assert_eq!(tree.max(), expected.max());
+        assert_eq!(tree.median(), expected.median());
+        assert_eq!(tree.canonical_contents_bytes(), expected.canonical_contents_bytes());
+        assert_eq!(tree.value_size_histogram(&[0]), vec![0, 6]);
+        assert!(tree.merge_join(&expected).all(|item| matches!(item, MergeItem::Both(..))));
+        assert!(tree.make_patch(&expected).ops.is_empty());
+        assert!(tree.would_change(3, &String::new()));
+
+        tree.insert(3, String::new()).unwrap();
+        assert_eq!(tree.len(), 7);
+        assert!(!tree.would_change(3, &String::new()));
+        assert_eq!(tree.keep_highest(4), 3);
+        assert_eq!(tree.iter().map(|(&key, _)| key).collect::<Vec<_>>(), vec![4, 5, 7, 8]);
+        assert_eq!(tree.audit_report().len, 4);
+    }
+
+    #[test]
+    fn test_compact_removes_tombstones() {
+        let mut tree = MerkleAvlTree::new();
+        let mut expected = MerkleAvlTree::new();
+        for key in 0..20 {
+            tree.insert(key, key.to_string()).unwrap();
+            expected.insert(key, key.to_string()).unwrap();
+        }
+        for key in [3, 11, 12] {
+            tree.soft_delete(key).unwrap();
+            expected.delete(key).unwrap();
+        }
+        assert_eq!(tree.compact(), 3);
+        assert_eq!(tree.compact(), 0);
+        assert_eq!(tree.len(), 17);
+        assert_eq!(tree.lookup(11), Err(Error::NotFound));
+        assert!(tree.iter().eq(expected.iter()));
+
+        tree.soft_delete(5).unwrap();
+        tree.insert(5, "back".to_string()).unwrap();
+        assert_eq!(tree.lookup(5).as_deref(), Ok(&"back".to_string()));
+    }
+
+    fn tombstoned(keys: std::ops::RangeInclusive<Key>, deleted: &[Key]) -> MerkleAvlTree {
+        let mut tree = MerkleAvlTree::new();
+        for key in keys {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        for &key in deleted {
+            tree.soft_delete(key).unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn test_lca_skips_tombstones() {
+        // 4 at the root over 2 (1, 3) and 6 (5, 7).
+        let tree = tombstoned(1..=7, &[2]);
+        assert_eq!(tree.lca(2, 3), None);
+        assert_eq!(tree.lca(1, 3), None);
+        assert_eq!(tree.lca(1, 5), Some((&4, Cow::Owned("4".to_string()))));
+    }
+
+    #[test]
+    fn test_self_check_proof_of_tombstone() {
+        let tree = tombstoned(0..=9, &[3]);
+        assert_eq!(tree.self_check_proof(3), Ok(()));
+        assert_eq!(tree.self_check_proof(4), Ok(()));
+        assert_eq!(tree.self_check_proof(20), Ok(()));
+    }
+
+    #[test]
+    fn test_subtree_proofs_skip_tombstones() {
+        let tree = tombstoned(0..=30, &[5]);
+        let shard = tree.root.as_ref().unwrap().left.as_ref().unwrap().key;
+        let proofs = tree.subtree_proofs(shard).unwrap();
+        assert_eq!(proofs.len(), 14);
+        assert!(proofs.iter().all(|(key, ..)| *key != 5));
+    }
+
+    #[test]
+    fn test_key_diff_skips_tombstones() {
+        let tree = tombstoned(1..=3, &[2]);
+        let mut other = MerkleAvlTree::new();
+        other.insert(1, "1".to_string()).unwrap();
+        other.insert(3, "3".to_string()).unwrap();
+        assert_eq!(tree.key_diff(&other), (vec![], vec![]));
+        other.insert(2, "2".to_string()).unwrap();
+        assert_eq!(tree.key_diff(&other), (vec![], vec![2]));
+        assert_eq!(other.key_diff(&tree), (vec![2], vec![]));
+    }
+
+    #[test]
+    fn test_shard_balances_live_entries() {
+        let tree = tombstoned(0..=7, &[1, 2, 3, 4]);
+        let sizes: Vec<_> = tree.shard(2).iter().map(|shard| shard.len()).collect();
+        assert_eq!(sizes, vec![2, 2]);
+        let tree = tombstoned(0..=7, &[5, 6, 7]);
+        let shards = tree.shard(3);
+        let sizes: Vec<_> = shards.iter().map(|shard| shard.len()).collect();
+        assert_eq!(sizes, vec![2, 2, 1]);
+        assert_eq!(shards[2].lookup(7), Err(Error::Deleted));
+    }
+
+    #[test]
+    fn test_completeness_proof_over_tombstones() {
+        let tree = tombstoned(1..=5, &[3]);
+        let root = tree.root_hash().unwrap();
+        let proof = tree.generate_completeness_proof(2, 4).unwrap();
+        let found = MerkleAvlTree::verify_completeness_proof(&proof, root).unwrap();
+        let keys: Vec<_> = found.into_iter().map(|(&key, _)| key).collect();
+        assert_eq!(keys, vec![2, 4]);
+
+        let tree = tombstoned(1..=5, &[3, 5]);
+        let root = tree.root_hash().unwrap();
+        let proof = tree.generate_completeness_proof(3, 4).unwrap();
+        let found = MerkleAvlTree::verify_completeness_proof(&proof, root).unwrap();
+        assert_eq!(found, vec![(&4, &"4".to_string())]);
+        let mut short = proof.clone();
+        short.hi = 3;
+        assert_eq!(
+            MerkleAvlTree::verify_completeness_proof(&short, root),
+            Err(Error::InvalidProof)
+        );
+    }
+
+    #[test]
+    fn test_neighbor_proof_next_to_tombstone() {
+        let tree = tombstoned(1..=5, &[3]);
+        let root = tree.root_hash().unwrap();
+        for key in [2, 3, 4] {
+            let proof = tree.neighbor_proof(key).unwrap();
+            assert_eq!(MerkleAvlTree::verify_neighbor_proof(&proof, root), Ok(()), "{}", key);
+        }
+    }
+
+    #[test]
+    fn test_delete_of_tombstone() {
+        let mut tree = tombstoned(0..=9, &[7]);
+        let stream = tree.subscribe();
+        let root = tree.root_hash();
+        assert_eq!(tree.delete(7), Err(Error::Deleted));
+        assert_eq!(tree.root_hash(), root);
+        assert!(stream.try_recv().is_err());
+        assert_eq!(tree.compact(), 1);
+        assert_eq!(tree.delete(7), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn test_apply_patch_over_tombstone() {
+        let mut tree = tombstoned(1..=3, &[2]);
+        let mut target = MerkleAvlTree::new();
+        for (key, value) in [(2, "x"), (3, "3"), (4, "4")] {
+            target.insert(key, value.to_string()).unwrap();
+        }
+        for op in [PatchOp::Update(2, "y".to_string()), PatchOp::Delete(2)] {
+            let patch = Patch { ops: vec![op] };
+            assert_eq!(tree.apply_patch(&patch), Err(Error::PatchConflict));
+        }
+        let patch = tree.make_patch(&target);
+        assert_eq!(tree.apply_patch(&patch), Ok(()));
+        assert!(tree.iter().eq(target.iter()));
+    }
+
+    #[test]
+    fn test_proof_frontier_rebuilds_root() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..100 {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        let root = tree.root_hash();
+        let keys = [12, 13, 14, 80, 13];
+        let frontier = tree.proof_frontier(&keys).unwrap();
+        let path_nodes = tree.frontier_path_nodes(&keys).unwrap();
+        assert!(frontier.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        // Far fewer hashes than one full proof per key.
+        assert!(frontier.len() < 3 * tree.height() as usize, "{}", frontier.len());
+        for key in [12, 13, 14, 80] {
+            let value_hash = tree.hash_value(&key.to_string());
+            assert!(path_nodes.contains(&(key, value_hash)));
+        }
+        assert_eq!(MerkleAvlTree::root_from_frontier(&path_nodes, &frontier), root);
+
+        let mut tampered = frontier.clone();
+        tampered[0].1 = fake_hash(1);
+        assert_ne!(MerkleAvlTree::root_from_frontier(&path_nodes, &tampered), root);
+        assert_ne!(MerkleAvlTree::root_from_frontier(&path_nodes, &frontier[1..]), root);
+        let stray = [frontier.clone(), vec![(1000, EMPTY_HASH)]].concat();
+        assert_eq!(MerkleAvlTree::root_from_frontier(&path_nodes, &stray), None);
+        let all: Vec<Key> = (0..100).collect();
+        assert!(tree.proof_frontier(&all).unwrap().is_empty());
+        assert_eq!(tree.proof_frontier(&[5, 500]), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn test_subscribe_streams_mutations_to_a_replica() {
+        let mut tree = MerkleAvlTree::new();
+        tree.insert(0, "before".to_string()).unwrap();
+        let mut replica = tree.clone();
+        let stream = tree.subscribe();
+        for key in 1..30 {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        for key in (0..30).step_by(3) {
+            tree.delete(key).unwrap();
+        }
+        tree.insert(4, "changed".to_string()).unwrap();
+        assert_eq!(tree.delete(3), Err(Error::NotFound));
+
+        let mut count = 0;
+        for (op, root) in stream.try_iter() {
+            match op {
+                Operation::Insert(key, value) => replica.insert(key, value).unwrap(),
+                Operation::Delete(key) => replica.delete(key).unwrap(),
+                Operation::Lookup(_) => unreachable!(),
+            }
+            assert_eq!(replica.root_hash().map(|root| root.0), Some(root));
+            count += 1;
+        }
+        assert_eq!(count, 29 + 10 + 1);
+        assert_eq!(replica.root_hash(), tree.root_hash());
+
+        drop(stream);
+        tree.insert(100, String::new()).unwrap();
+        assert!(tree.subscribers.0.is_empty());
+    }
+
+    #[test]
+    fn test_max_height_bounds_trees_and_proofs() {
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(MerkleAvlTree::MAX_HEIGHT, 91);
+        let entries = (0..1 << 18).map(|key| (key, String::new())).collect();
+        let tree = MerkleAvlTree::from_sorted(entries);
+        assert!(tree.height() < MerkleAvlTree::MAX_HEIGHT);
+
+        let padded = (0..MerkleAvlTree::MAX_HEIGHT + 1).fold(ProofNode::Empty, |child, key| {
+            ProofNode::Left(key, EMPTY_HASH, Box::new(child), None)
+        });
+        let root = RootHash(padded.hash(NO_SALT));
+        assert_eq!(
+            MerkleAvlTree::verify_proof_bounded(&padded, root, usize::MAX),
+            Err(Error::ProofTooDeep)
+        );
+        assert_eq!(ProofNode::from_bytes(&padded.to_bytes()), Err(Error::ProofTooDeep));
+        let proof = tree.generate_proof(200_000).unwrap();
+        assert_eq!(ProofNode::from_bytes(&proof.to_bytes()), Ok(proof.clone()));
+    }
+
+    #[test]
+    fn test_proof_cache_shrinks_nearby_proofs() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..127 {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        let root = tree.root_hash().unwrap();
+        let cache = MerkleAvlTree::cache_from_proof(&tree.generate_proof(40).unwrap());
+        assert_eq!(cache.root(), Some(root));
+
+        for key in [40, 41, 44, 38, 100] {
+            let full = tree.generate_proof(key).unwrap();
+            let shrunk = cache.shrink(key, &full);
+            let value = key.to_string();
+            assert_eq!(
+                MerkleAvlTree::verify_membership_with_cache(&shrunk, &cache, root, key),
+                Ok(value),
+                "key {}",
+                key
+            );
+            assert!(shrunk.steps().0.len() <= full.steps().0.len());
+        }
+        // 41 sits right next to 40, so most of its path is already cached.
+        let full = tree.generate_proof(41).unwrap();
+        assert!(cache.shrink(41, &full).steps().0.len() + 3 < full.steps().0.len());
+
+        let shrunk = cache.shrink(41, &full);
+        assert!(MerkleAvlTree::verify_membership_with_cache(&shrunk, &cache, root, 42).is_err());
+        // Without the cache the cut proof proves nothing.
+        assert_eq!(MerkleAvlTree::verify_membership(&shrunk, root, 41), Err(Error::InvalidProof));
+        let mut other = tree.clone();
+        other.insert(1000, String::new()).unwrap();
+        let other_root = other.root_hash().unwrap();
+        assert_eq!(
+            MerkleAvlTree::verify_membership_with_cache(&shrunk, &cache, other_root, 41),
+            Err(Error::InvalidProof)
+        );
+    }
+
+    #[test]
+    fn test_value_size_histogram() {
+        let mut tree = MerkleAvlTree::new();
+        for (key, len) in [0, 1, 4, 5, 10, 11, 100, 3].iter().enumerate() {
+            tree.insert(key as Key, "x".repeat(*len)).unwrap();
+        }
+        assert_eq!(tree.value_size_histogram(&[4, 10]), vec![4, 2, 2]);
+        assert_eq!(tree.value_size_histogram(&[]), vec![8]);
+        assert_eq!(MerkleAvlTree::new().value_size_histogram(&[1]), vec![0, 0]);
+    }
+
+    #[test]
+    fn test_hidden_value_proofs() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..20 {
+            tree.insert(key, format!("secret {}", key)).unwrap();
+        }
+        let root = tree.root_hash().unwrap();
+        let proof = tree.generate_proof_hidden(7).unwrap();
+        assert!(!format!("{:?}", proof).contains("secret 7"));
+
+        let nodes = MerkleAvlTree::proof_to_mpt_nodes(&proof);
+        assert_eq!(MerkleAvlTree::proof_from_mpt_nodes(&nodes).unwrap(), proof);
+
+        // Withholding the value checks the structure only.
+        assert_eq!(MerkleAvlTree::verify_proof_hidden(&proof, root, None), Ok(7));
+        // Supplying it checks it against the committed hash.
+        let value = "secret 7".to_string();
+        assert_eq!(MerkleAvlTree::verify_proof_hidden(&proof, root, Some(&value)), Ok(7));
+        let wrong = "secret 8".to_string();
+        assert_eq!(
+            MerkleAvlTree::verify_proof_hidden(&proof, root, Some(&wrong)),
+            Err(Error::InvalidProof)
+        );
+
+        tree.insert(7, "changed".to_string()).unwrap();
+        let new_root = tree.root_hash().unwrap();
+        let hidden = MerkleAvlTree::verify_proof_hidden(&proof, new_root, None);
+        assert_eq!(hidden, Err(Error::InvalidProof));
+        // Ordinary and absence proofs don't pass as hidden ones.
+        let plain = tree.generate_proof(7).unwrap();
+        let hidden = MerkleAvlTree::verify_proof_hidden(&plain, new_root, None);
+        assert_eq!(hidden, Err(Error::InvalidProof));
+        let absent = tree.generate_proof_hidden(100).unwrap();
+        let hidden = MerkleAvlTree::verify_proof_hidden(&absent, new_root, None);
+        assert_eq!(hidden, Err(Error::InvalidProof));
+    }
+
+    #[test]
+    fn test_from_proofs() {
+        let mut tree = MerkleAvlTree::new();
+        for key in [50, 20, 80, 10, 30, 70, 90, 25, 5, 60] {
+            tree.insert(key, format!("v{}", key)).unwrap();
+        }
+        let root = tree.root_hash().unwrap();
+        let mut proofs: Vec<ProofNode> = tree
+            .iter()
+            .map(|(key, _)| tree.generate_proof(*key).unwrap())
+            .collect();
+        proofs.reverse();
+        let rebuilt = MerkleAvlTree::from_proofs(&proofs, root).unwrap();
+        assert_eq!(rebuilt.root_hash(), Some(root));
+        assert_eq!(rebuilt.len(), tree.len());
+        assert_eq!(rebuilt.lookup(25).as_deref(), Ok(&"v25".to_string()));
+        assert_eq!(rebuilt.find_invariant_violation(), None);
+
+        // A leaf's proof alone leaves its ancestors' values unknown.
+        let leaf_only = [tree.generate_proof(5).unwrap()];
+        assert_eq!(MerkleAvlTree::from_proofs(&leaf_only, root), Err(Error::Incomplete));
+        let missing_child: Vec<ProofNode> = proofs
+            .iter()
+            .filter(|proof| proof.key_value().map(|(key, _)| *key) != Some(90))
+            .cloned()
+            .collect();
+        assert_eq!(MerkleAvlTree::from_proofs(&missing_child, root), Err(Error::Incomplete));
+        assert_eq!(MerkleAvlTree::from_proofs(&[], root), Err(Error::Incomplete));
+
+        tree.insert(25, "changed".to_string()).unwrap();
+        proofs.push(tree.generate_proof(25).unwrap());
+        assert_eq!(MerkleAvlTree::from_proofs(&proofs, root), Err(Error::InvalidProof));
+    }
+
+    #[test]
+    fn test_key_range() {
+        let mut tree = MerkleAvlTree::with_key_range(100, 199);
+        for key in [100, 150, 199] {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        let before = tree.clone();
+        assert_eq!(tree.insert(99, String::new()), Err(Error::KeyOutOfRange));
+        assert_eq!(tree.insert(200, String::new()), Err(Error::KeyOutOfRange));
+        assert_eq!(tree.insert(Key::MIN, String::new()), Err(Error::KeyOutOfRange));
+        assert_eq!(tree, before);
+        assert_eq!(tree.len(), 3);
+        let op = Operation::Insert(200, String::new());
+        assert_eq!(tree.next_root(&op), Err(Error::KeyOutOfRange));
+    }
+
+    #[test]
+    fn test_change_count_since() {
+        let pairs = (0..100).map(|key| (key, key.to_string())).collect();
+        let mut tree = MerkleAvlTree::from_sorted(pairs);
+        let snapshot = tree.clone();
+        assert_eq!(tree.change_count_since(&snapshot), 0);
+
+        tree.insert(1000, "added".to_string()).unwrap();
+        tree.delete(40).unwrap();
+        tree.insert(70, "changed".to_string()).unwrap();
+        assert_eq!(tree.change_count_since(&snapshot), 3);
+        assert_eq!(snapshot.change_count_since(&tree), 3);
+        assert_eq!(snapshot.change_count_since(&MerkleAvlTree::new()), 100);
+    }
+
+    #[test]
+    fn test_change_count_since_skips_tombstones() {
+        let tree = tombstoned(0..=9, &[5]);
+        let mut old = tombstoned(0..=9, &[]);
+        assert_eq!(tree.change_count_since(&old), 1);
+        assert_eq!(old.change_count_since(&tree), 1);
+        old.delete(5).unwrap();
+        assert_eq!(tree.change_count_since(&old), 0);
+        assert_eq!(old.change_count_since(&tree), 0);
+        assert_eq!(tree.change_count_since(&tombstoned(0..=9, &[5, 6])), 1);
+    }
+
+    #[test]
+    fn test_hash_width_round_trip() {
+        let expected_bytes = if cfg!(feature = "hash-256") {
+            32
+        } else if cfg!(feature = "hash-128") {
+            16
+        } else {
+            8
+        };
+        assert_eq!(HASH_BYTES, expected_bytes);
+
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..50 {
+            tree.insert(key, format!("v{}", key)).unwrap();
+        }
+        let root = tree.root_hash().unwrap();
+        assert_eq!(root.to_string().len(), 2 * HASH_BYTES);
+        for key in [0, 17, 49, 100] {
+            let proof = tree.generate_proof(key).unwrap();
+            let decoded =
+                MerkleAvlTree::proof_from_mpt_nodes(&MerkleAvlTree::proof_to_mpt_nodes(&proof));
+            assert_eq!(decoded.as_ref(), Ok(&proof));
+        }
+        let proof = tree.generate_proof(17).unwrap();
+        assert_eq!(MerkleAvlTree::verify_membership(&proof, root, 17), Ok("v17".to_string()));
+        let mut nodes = MerkleAvlTree::proof_to_mpt_nodes(&proof);
+        nodes[0].pop();
+        assert_eq!(MerkleAvlTree::proof_from_mpt_nodes(&nodes), Err(Error::MalformedProof));
+    }
+
+    #[test]
+    fn test_is_subset_of() {
+        let pairs = (0..64).map(|key| (key, key.to_string())).collect();
+        let superset = MerkleAvlTree::from_sorted(pairs);
+        let mut subset = superset.clone();
+        for key in (0..64).step_by(3) {
+            subset.delete(key).unwrap();
+        }
+        assert!(subset.is_subset_of(&superset));
+        assert!(superset.is_subset_of(&superset));
+        assert!(MerkleAvlTree::new().is_subset_of(&superset));
+        assert!(!superset.is_subset_of(&subset));
+
+        let mut extra = subset.clone();
+        extra.insert(1000, String::new()).unwrap();
+        assert!(!extra.is_subset_of(&superset));
+
+        let mut mismatch = subset.clone();
+        mismatch.insert(10, "changed".to_string()).unwrap();
+        assert!(!mismatch.is_subset_of(&superset));
+    }
+
+    #[test]
+    fn test_is_subset_of_skips_tombstones() {
+        let tree = tombstoned(1..=2, &[2]);
+        let mut other = MerkleAvlTree::new();
+        other.insert(1, "1".to_string()).unwrap();
+        assert!(tree.is_subset_of(&other));
+        let padded = tombstoned(0..=1, &[0]);
+        assert!(other.is_subset_of(&padded));
+        assert!(!tombstoned(1..=2, &[]).is_subset_of(&tree));
+    }
+
+    #[test]
+    fn test_key_spacing_stats() {
+        let pairs = (0..20).map(|i| (i * 10, String::new())).collect();
+        let uniform = MerkleAvlTree::from_sorted(pairs);
+        let stats = uniform.key_spacing_stats();
+        assert_eq!((stats.gaps, stats.min, stats.max), (19, 10, 10));
+        assert_eq!(stats.mean, 10.0);
+        assert!(stats.stddev < 1e-9);
+
+        let mut clustered = MerkleAvlTree::new();
+        for key in (0..10).chain(10_000..10_010) {
+            clustered.insert(key, String::new()).unwrap();
+        }
+        let stats = clustered.key_spacing_stats();
+        assert_eq!((stats.gaps, stats.min, stats.max), (19, 1, 9_991));
+        assert!(stats.stddev > 2_000.0);
+
+        let mut extremes = MerkleAvlTree::new();
+        extremes.insert(Key::MIN, String::new()).unwrap();
+        extremes.insert(Key::MAX, String::new()).unwrap();
+        assert_eq!(extremes.key_spacing_stats().max, u64::from(u32::MAX));
+        extremes.delete(Key::MAX).unwrap();
+        assert_eq!(extremes.key_spacing_stats(), SpacingStats::default());
+    }
+
+    #[test]
+    fn test_swap_values() {
+        let mut tree = MerkleAvlTree::with_collision_check();
+        for key in 0..15 {
+            tree.insert(key, format!("v{}", key)).unwrap();
+        }
+        let before = tree.clone();
+        let root = tree.root_hash();
+        assert_eq!(tree.swap_values(3, 99), Err(Error::NotFound));
+        assert_eq!(tree.swap_values(99, 3), Err(Error::NotFound));
+        assert_eq!(tree, before);
+
+        tree.swap_values(2, 13).unwrap();
+        assert_eq!(tree.lookup(2).as_deref(), Ok(&"v13".to_string()));
+        assert_eq!(tree.lookup(13).as_deref(), Ok(&"v2".to_string()));
+        assert_ne!(tree.root_hash(), root);
+        assert!(tree.verify_hashes());
+        let mut expected = before.clone();
+        expected.insert(2, "v13".to_string()).unwrap();
+        expected.insert(13, "v2".to_string()).unwrap();
+        assert_eq!(tree.root_hash(), expected.root_hash());
+        let leaf_hashes = tree.leaf_hashes.as_ref().unwrap();
+        assert!(leaf_hashes.values().any(|(key, value)| (*key, value.as_str()) == (2, "v13")));
+        assert_eq!(leaf_hashes.len(), 15);
+
+        tree.swap_values(2, 13).unwrap();
+        assert_eq!(tree, before);
+    }
+
+    #[test]
+    fn test_level_order() {
+        assert!(MerkleAvlTree::new().level_order().is_empty());
+        // Seven sorted keys build a perfect tree rooted at 4.
+        let pairs = (1..=7).map(|key| (key, key.to_string())).collect();
+        let mut tree = MerkleAvlTree::from_sorted(pairs);
+        let levels = |tree: &MerkleAvlTree| -> Vec<(usize, Key)> {
+            tree.level_order().into_iter().map(|(depth, key, _)| (depth, key)).collect()
+        };
+        assert_eq!(levels(&tree), vec![(0, 4), (1, 2), (1, 6), (2, 1), (2, 3), (2, 5), (2, 7)]);
+        assert_eq!(tree.level_order()[5], (2, 5, "5".to_string()));
+        tree.soft_delete(2).unwrap();
+        assert_eq!(levels(&tree), vec![(0, 4), (1, 6), (2, 1), (2, 3), (2, 5), (2, 7)]);
+    }
+
+    #[test]
+    fn test_keep_highest() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 1..=20 {
+            tree.insert(key, key.to_string()).unwrap();
+        }
+        assert_eq!(tree.keep_highest(20), 0);
+        assert_eq!(tree.keep_highest(5), 15);
+        let keys: Vec<Key> = tree.iter().map(|(key, _)| *key).collect();
+        assert_eq!(keys, (16..=20).collect::<Vec<Key>>());
+        assert_eq!(tree.len(), 5);
+        assert_eq!(tree.find_invariant_violation(), None);
+
+        assert_eq!(tree.keep_highest(0), 5);
+        assert!(tree.is_empty());
+        assert_eq!(tree.keep_highest(3), 0);
+    }
+
+    #[test]
+    fn test_verify_transition() {
+        let mut initial = MerkleAvlTree::new();
+        for key in 0..10 {
+            initial.insert(key, key.to_string()).unwrap();
+        }
+        let ops = vec![
+            Operation::Insert(10, "ten".to_string()),
+            Operation::Delete(3),
+            Operation::Lookup(3),
+            Operation::Insert(4, "four".to_string()),
+        ];
+        let mut after = initial.clone();
+        after.insert(10, "ten".to_string()).unwrap();
+        after.delete(3).unwrap();
+        after.insert(4, "four".to_string()).unwrap();
+        let expected = after.root_hash().unwrap();
+
+        assert!(MerkleAvlTree::verify_transition(&initial, &ops, expected));
+        assert!(!MerkleAvlTree::verify_transition(&initial, &ops, RootHash::from(fake_hash(1))));
+        assert!(!MerkleAvlTree::verify_transition(&initial, &ops[..2], expected));
+        // Deleting a missing key fails the transition even at the right root.
+        let failing = [Operation::Delete(42)];
+        let unchanged = initial.root_hash().unwrap();
+        assert!(!MerkleAvlTree::verify_transition(&initial, &failing, unchanged));
+        assert!(MerkleAvlTree::verify_transition(&initial, &[], unchanged));
+        assert_eq!(initial.len(), 10);
+    }
+
+    #[test]
+    fn test_verify_transition_keeps_probe_silent() {
+        let visited = std::rc::Rc::new(std::cell::Cell::new(0));
+        let sink = visited.clone();
+        let mut initial = MerkleAvlTree::with_probe(move |_| sink.set(sink.get() + 1));
+        for key in 0..10 {
+            initial.insert(key, key.to_string()).unwrap();
+        }
+        let ops = [Operation::Insert(10, "ten".to_string()), Operation::Lookup(4)];
+        let mut after = initial.clone();
+        after.insert(10, "ten".to_string()).unwrap();
+        let expected = after.root_hash().unwrap();
+        visited.set(0);
+        assert!(MerkleAvlTree::verify_transition(&initial, &ops, expected));
+        assert_eq!(visited.get(), 0);
+    }
+
+    #[test]
+    fn test_swap_values_rejects_tombstones() {
+        let mut tree = MerkleAvlTree::new();
+        for key in 0..10 {
+            tree.insert(key, format!("v{}", key)).unwrap();
+        }
+        tree.soft_delete(4).unwrap();
+        let before = tree.clone();
+        assert_eq!(tree.swap_values(4, 7), Err(Error::Deleted));
+        assert_eq!(tree.swap_values(7, 4), Err(Error::Deleted));
+        assert_eq!(tree.swap_values(4, 4), Err(Error::Deleted));
+        assert_eq!(tree.swap_values(4, 99), Err(Error::NotFound));
+        assert_eq!(tree, before);
+        assert_eq!(tree.lookup(4), Err(Error::Deleted));
     }
 }